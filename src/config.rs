@@ -1,22 +1,132 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::net::SocketAddr;
 use std::path::Path;
-use std::time::Duration;
 
 use anyhow::{Context, Result};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::Method;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::features::health_check::parse_status_range;
+use crate::features::load_balancer::{LoadBalancingStrategy, MAX_WEIGHT};
+use crate::features::ratelimit::parse_cidr;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
-    /// Server configuration
-    pub server: ServerConfig,
+    /// Single listener configuration, served with the top-level routes
+    pub server: Option<ServerConfig>,
+    
+    /// Additional listeners, each with its own routes
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
     
     /// Backends configuration
     pub backends: HashMap<String, BackendConfig>,
     
-    /// Routes configuration
+    /// Routes configuration for the `server` listener
+    #[serde(default)]
     pub routes: Vec<RouteConfig>,
+    
+    /// Named route sets that listeners can share
+    #[serde(default)]
+    pub route_groups: HashMap<String, Vec<RouteConfig>>,
+    
+    /// How errors generated by the proxy itself are rendered
+    #[serde(default)]
+    pub error_responses: ErrorResponseConfig,
+    
+    /// Rate limit for routes without their own policy (optional)
+    pub rate_limit: Option<RateLimitConfig>,
+    
+    /// Seconds between metrics summaries in the log, 0 turns them off
+    #[serde(default = "default_metrics_log_interval")]
+    pub metrics_log_interval: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RateLimitConfig {
+    /// How requests are counted
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    
+    /// Requests allowed per client in each window
+    pub limit: u32,
+    
+    /// Window length in seconds
+    #[serde(default = "default_rate_limit_window")]
+    pub window: u64,
+    
+    /// Extra requests a client may send at once above the steady rate (token-bucket and gcra)
+    #[serde(default)]
+    pub burst: u32,
+    
+    /// What identifies a client, several parts are combined into one key
+    #[serde(default = "default_rate_limit_key")]
+    pub key: Vec<RateLimitKeyConfig>,
+    
+    /// Peer addresses or CIDR ranges that are never limited
+    #[serde(default)]
+    pub allow_list: Vec<String>,
+    
+    /// Clients tracked at most, the least recently seen is forgotten past this
+    #[serde(default = "default_rate_limit_max_keys")]
+    pub max_keys: usize,
+    
+    /// Send RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset on responses
+    #[serde(default)]
+    pub headers: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RateLimitKeyConfig {
+    /// Where this part of the key comes from
+    pub source: RateLimitKeySource,
+    
+    /// Header name or JWT claim for those sources
+    pub name: Option<String>,
+    
+    /// Path template for the path source, e.g. "/users/{id}/*"
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKeySource {
+    PeerAddress,
+    ClientIp,
+    Header,
+    JwtClaim,
+    Path,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitAlgorithm {
+    #[default]
+    SlidingWindow,
+    TokenBucket,
+    Gcra,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ErrorResponseConfig {
+    /// Body format when no template matches the status
+    #[serde(default)]
+    pub format: ErrorFormat,
+    
+    /// Template files by status code, `{{status}}` and `{{reason}}` are filled in
+    #[serde(default)]
+    pub templates: HashMap<u16, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -26,10 +136,140 @@ pub struct ServerConfig {
     
     /// TLS configuration (optional)
     pub tls: Option<TlsConfig>,
+    
+    /// Error pages for every route of this listener
+    #[serde(default)]
+    pub error_pages: Vec<ErrorPageConfig>,
+    
+    /// Replace upstream 5xx bodies with the matching error page
+    #[serde(default)]
+    pub intercept_errors: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ListenerConfig {
+    /// Name used in logs (defaults to the listen address)
+    pub name: Option<String>,
+    
+    /// Address to bind the listener to
+    pub listen_addr: SocketAddr,
+    
+    /// TLS configuration (optional)
+    pub tls: Option<TlsConfig>,
+    
+    /// Routes served by this listener
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    
+    /// Route group to serve instead of inline routes
+    pub route_group: Option<String>,
+    
+    /// Redirect every request to HTTPS instead of proxying (optional)
+    pub redirect: Option<HttpsRedirectConfig>,
+    
+    /// Error pages for every route of this listener
+    #[serde(default)]
+    pub error_pages: Vec<ErrorPageConfig>,
+    
+    /// Replace upstream 5xx bodies with the matching error page
+    #[serde(default)]
+    pub intercept_errors: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HttpsRedirectConfig {
+    /// Redirect status code (301, 302, 307 or 308)
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    
+    /// Port of the HTTPS listener to redirect to
+    #[serde(default = "default_https_port")]
+    pub https_port: u16,
+}
+
+impl Config {
+    /// All listeners to start, with the `server` block first when present
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = Vec::with_capacity(self.listeners.len() + 1);
+        
+        if let Some(server) = &self.server {
+            listeners.push(ListenerConfig {
+                name: None,
+                listen_addr: server.listen_addr,
+                tls: server.tls.clone(),
+                routes: self.routes.clone(),
+                route_group: None,
+                redirect: None,
+                error_pages: server.error_pages.clone(),
+                intercept_errors: server.intercept_errors,
+            });
+        }
+        
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
+    
+    /// Every header any listener uses to pass a client certificate upstream,
+    /// including the defaults
+    pub fn client_cert_header_names(&self) -> Vec<String> {
+        let mut names = vec![default_client_cert_header(), default_client_cert_san_header()];
+        
+        for tls in self.listeners().iter().filter_map(|listener| listener.tls.as_ref()) {
+            for name in [&tls.client_cert_header, &tls.client_cert_san_header] {
+                if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        
+        names
+    }
+    
+    /// Route table served by a listener
+    pub fn listener_routes<'a>(&'a self, listener: &'a ListenerConfig) -> &'a [RouteConfig] {
+        match &listener.route_group {
+            Some(group) => self.route_groups.get(group).map(Vec::as_slice).unwrap_or(&[]),
+            None => &listener.routes,
+        }
+    }
+}
+
+impl ListenerConfig {
+    /// Name to use for this listener in logs
+    pub fn display_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.listen_addr.to_string())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TlsConfig {
+    /// Path to the default certificate file
+    pub cert_path: String,
+    
+    /// Path to the default private key file
+    pub key_path: String,
+    
+    /// Additional certificates selected by the SNI server name
+    #[serde(default)]
+    pub certificates: Vec<SniCertificateConfig>,
+    
+    /// CA bundle used to require and verify client certificates (optional)
+    pub client_ca_path: Option<String>,
+    
+    /// Header carrying the verified client certificate subject upstream
+    #[serde(default = "default_client_cert_header")]
+    pub client_cert_header: String,
+    
+    /// Header carrying the verified client certificate SANs upstream
+    #[serde(default = "default_client_cert_san_header")]
+    pub client_cert_san_header: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SniCertificateConfig {
+    /// Server name to match, either exact or a "*.example.com" wildcard
+    pub server_name: String,
+    
     /// Path to the certificate file
     pub cert_path: String,
     
@@ -39,26 +279,142 @@ pub struct TlsConfig {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BackendConfig {
-    /// List of backend server addresses
-    pub servers: Vec<String>,
+    /// List of backend servers, either plain URLs or objects with a weight
+    pub servers: Vec<BackendServerConfig>,
     
-    /// Load balancing method (round-robin, least-connections, etc.)
+    /// Load balancing method (round-robin, weighted-round-robin, least-connections, consistent-hash or p2c-ewma)
     #[serde(default = "default_load_balancing")]
     pub load_balancing: String,
     
+    /// Request attribute hashed by consistent-hash (defaults to the client IP)
+    pub hash_key: Option<HashKeyConfig>,
+    
     /// Health check configuration (optional)
     pub health_check: Option<HealthCheckConfig>,
     
+    /// Passive outlier detection for individual servers (optional)
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    
+    /// Circuit breaker settings for the backend as a whole
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    
     /// Connection timeout in seconds
     #[serde(default = "default_timeout")]
     pub timeout: u64,
+    
+    /// TLS settings for https:// servers (optional)
+    pub tls: Option<UpstreamTlsConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(from = "BackendServerEntry")]
+pub struct BackendServerConfig {
+    /// Server address, e.g. "http://localhost:3000"
+    pub url: String,
+    
+    /// Relative share of traffic for weighted strategies
+    pub weight: u32,
+    
+    /// Only receives traffic when every primary server is unhealthy
+    pub backup: bool,
+}
+
+/// Server entries may be written as a plain URL string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackendServerEntry {
+    Url(String),
+    Detailed {
+        url: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+        #[serde(default)]
+        backup: bool,
+    },
+}
+
+impl From<BackendServerEntry> for BackendServerConfig {
+    fn from(entry: BackendServerEntry) -> Self {
+        match entry {
+            BackendServerEntry::Url(url) => BackendServerConfig {
+                url,
+                weight: default_weight(),
+                backup: false,
+            },
+            BackendServerEntry::Detailed { url, weight, backup } => BackendServerConfig { url, weight, backup },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HashKeyConfig {
+    /// Where the key comes from
+    pub source: HashKeySource,
+    
+    /// Header or cookie name for those sources
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashKeySource {
+    ClientIp,
+    Header,
+    Cookie,
+    Path,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct UpstreamTlsConfig {
+    /// CA bundle to verify servers with instead of the system roots
+    pub ca_path: Option<String>,
+    
+    /// Server name to send as SNI and verify the certificate against
+    pub server_name: Option<String>,
+    
+    /// Skip certificate verification entirely (testing only)
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    
+    /// Client certificate to present to servers that require mTLS
+    pub client_cert: Option<String>,
+    
+    /// Private key for the client certificate
+    pub client_key: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct HealthCheckConfig {
+    /// Whether to send an HTTP request or only open a TCP connection
+    #[serde(rename = "type", default)]
+    pub check_type: HealthCheckType,
+    
     /// Path to use for health check
+    #[serde(default = "default_health_path")]
     pub path: String,
     
+    /// HTTP method of the probe request
+    #[serde(default = "default_health_method")]
+    pub method: String,
+    
+    /// Extra request headers sent with the probe
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    
+    /// Host header to send instead of the server's address (optional)
+    pub host: Option<String>,
+    
+    /// Status codes that count as healthy, a single code or a range like "200-299"
+    #[serde(default = "default_health_expected_status")]
+    pub expected_status: String,
+    
+    /// Substring the response body must contain (optional)
+    pub body_contains: Option<String>,
+    
+    /// Regex the response body must match (optional)
+    pub body_regex: Option<String>,
+    
     /// Interval between health checks in seconds
     #[serde(default = "default_health_interval")]
     pub interval: u64,
@@ -66,6 +422,104 @@ pub struct HealthCheckConfig {
     /// Timeout for health check in seconds
     #[serde(default = "default_health_timeout")]
     pub timeout: u64,
+    
+    /// Consecutive passed checks before a down server is marked up
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    
+    /// Consecutive failed checks before an up server is marked down
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OutlierDetectionConfig {
+    /// Consecutive 5xx responses or connect failures before a server is ejected
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    
+    /// Seconds a server stays ejected the first time, doubled on each repeat ejection
+    #[serde(default = "default_base_ejection_time")]
+    pub base_ejection_time: u64,
+    
+    /// Upper bound for the ejection time in seconds
+    #[serde(default = "default_max_ejection_time")]
+    pub max_ejection_time: u64,
+    
+    /// Largest share of the servers, in percent, that may be ejected at once
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Whether the circuit opens on consecutive failures or on the failure rate
+    #[serde(default)]
+    pub mode: CircuitBreakerMode,
+    
+    /// Consecutive failures that open the circuit
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    
+    /// Failure percentage within the window that opens the circuit in error-rate mode
+    #[serde(default = "default_failure_rate_threshold")]
+    pub failure_rate_threshold: u32,
+    
+    /// Length of the rolling window in seconds for error-rate mode
+    #[serde(default = "default_breaker_window")]
+    pub window: u64,
+    
+    /// Requests needed in the window before error-rate mode can open the circuit
+    #[serde(default = "default_minimum_requests")]
+    pub minimum_requests: u32,
+    
+    /// Response status codes counted as failures besides transport errors
+    #[serde(default)]
+    pub failure_status_codes: Vec<u16>,
+    
+    /// Seconds the circuit stays open before trial requests are let through
+    #[serde(default = "default_reset_timeout")]
+    pub reset_timeout: u64,
+    
+    /// Seconds the trial requests have to succeed before the circuit opens again
+    #[serde(default = "default_half_open_timeout")]
+    pub half_open_timeout: u64,
+    
+    /// Trial requests let through while half-open, all of them must succeed to close the circuit
+    #[serde(default = "default_half_open_max_requests")]
+    pub half_open_max_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            mode: CircuitBreakerMode::default(),
+            failure_threshold: default_failure_threshold(),
+            failure_rate_threshold: default_failure_rate_threshold(),
+            window: default_breaker_window(),
+            minimum_requests: default_minimum_requests(),
+            failure_status_codes: Vec::new(),
+            reset_timeout: default_reset_timeout(),
+            half_open_timeout: default_half_open_timeout(),
+            half_open_max_requests: default_half_open_max_requests(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitBreakerMode {
+    #[default]
+    Consecutive,
+    ErrorRate,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    #[default]
+    Http,
+    Tcp,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -79,16 +533,151 @@ pub struct RouteConfig {
     /// Strip prefix from request path
     #[serde(default)]
     pub strip_prefix: bool,
+    
+    /// Regex the client certificate subject or a SAN must match (optional)
+    pub client_cert_subject: Option<String>,
+    
+    /// Error pages for this route, taking precedence over the listener's
+    #[serde(default)]
+    pub error_pages: Vec<ErrorPageConfig>,
+    
+    /// Replace upstream 5xx bodies with the matching error page (defaults to the listener's setting)
+    pub intercept_errors: Option<bool>,
+    
+    /// Retry failed requests on another server (optional)
+    pub retry: Option<RetryConfig>,
+    
+    /// Race a second copy of slow idempotent requests on another server (optional)
+    pub hedge: Option<HedgeConfig>,
+    
+    /// Rate limit for this route instead of the global one (optional)
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HedgeConfig {
+    /// Milliseconds to wait for the first server before sending the copy
+    pub delay_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RetryConfig {
+    /// Retries after the first attempt
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    
+    /// Failures that are retried
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryCondition>,
+    
+    /// Upstream response statuses that are retried
+    #[serde(default)]
+    pub statuses: Vec<u16>,
+    
+    /// Also retry methods that aren't idempotent, such as POST
+    #[serde(default)]
+    pub non_idempotent: bool,
+    
+    /// Backoff before the first retry in milliseconds, doubled for each further retry
+    #[serde(default = "default_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+    
+    /// Upper bound for the backoff in milliseconds
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    
+    /// Retries allowed as a fraction of the route's requests
+    #[serde(default = "default_budget_ratio")]
+    pub budget_ratio: f64,
+    
+    /// Retries per second allowed regardless of the ratio
+    #[serde(default = "default_min_retries_per_second")]
+    pub min_retries_per_second: u32,
+    
+    /// Request bodies up to this size are buffered so they can be resent,
+    /// larger bodies are streamed and never retried
+    #[serde(default)]
+    pub buffer_body_bytes: usize,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: default_max_retries(),
+            retry_on: default_retry_on(),
+            statuses: Vec::new(),
+            non_idempotent: false,
+            backoff_base_ms: default_backoff_base_ms(),
+            backoff_max_ms: default_backoff_max_ms(),
+            budget_ratio: default_budget_ratio(),
+            min_retries_per_second: default_min_retries_per_second(),
+            buffer_body_bytes: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryCondition {
+    ConnectFailure,
+    Timeout,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorPageConfig {
+    /// Status code the page is served for
+    pub status: u16,
+    
+    /// File to serve as the body
+    pub file: Option<String>,
+    
+    /// Inline body, instead of a file
+    pub body: Option<String>,
+    
+    /// Content type of the body (defaults from the file extension, or text/plain)
+    pub content_type: Option<String>,
 }
 
 fn default_load_balancing() -> String {
     "round-robin".to_string()
 }
 
+fn default_client_cert_header() -> String {
+    "X-Client-Cert-Subject".to_string()
+}
+
+fn default_client_cert_san_header() -> String {
+    "X-Client-Cert-San".to_string()
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_https_port() -> u16 {
+    443
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
 fn default_timeout() -> u64 {
     30
 }
 
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_method() -> String {
+    "GET".to_string()
+}
+
+fn default_health_expected_status() -> String {
+    "200-399".to_string()
+}
+
 fn default_health_interval() -> u64 {
     10
 }
@@ -97,6 +686,102 @@ fn default_health_timeout() -> u64 {
     5
 }
 
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_base_ejection_time() -> u64 {
+    30
+}
+
+fn default_max_ejection_time() -> u64 {
+    300
+}
+
+fn default_max_ejection_percent() -> u32 {
+    50
+}
+
+fn default_metrics_log_interval() -> u64 {
+    60
+}
+
+fn default_rate_limit_window() -> u64 {
+    1
+}
+
+fn default_rate_limit_max_keys() -> usize {
+    100_000
+}
+
+fn default_rate_limit_key() -> Vec<RateLimitKeyConfig> {
+    vec![RateLimitKeyConfig {
+        source: RateLimitKeySource::PeerAddress,
+        name: None,
+        template: None,
+    }]
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_on() -> Vec<RetryCondition> {
+    vec![RetryCondition::ConnectFailure, RetryCondition::Timeout]
+}
+
+fn default_backoff_base_ms() -> u64 {
+    25
+}
+
+fn default_backoff_max_ms() -> u64 {
+    250
+}
+
+fn default_budget_ratio() -> f64 {
+    0.2
+}
+
+fn default_min_retries_per_second() -> u32 {
+    10
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_failure_rate_threshold() -> u32 {
+    50
+}
+
+fn default_breaker_window() -> u64 {
+    60
+}
+
+fn default_minimum_requests() -> u32 {
+    20
+}
+
+fn default_reset_timeout() -> u64 {
+    30
+}
+
+fn default_half_open_timeout() -> u64 {
+    10
+}
+
+fn default_half_open_max_requests() -> u32 {
+    1
+}
+
 pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config> {
     let config_content = fs::read_to_string(path)
         .context("Failed to read configuration file")?;
@@ -111,10 +796,102 @@ pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config> {
 
 fn validate_config(config: &Config) -> Result<()> {
     // Ensure all backends referenced in routes exist
-    for route in &config.routes {
+    let all_routes = config.routes.iter()
+        .chain(config.route_groups.values().flatten())
+        .chain(config.listeners.iter().flat_map(|listener| &listener.routes));
+    
+    for route in all_routes {
         if !config.backends.contains_key(&route.backend) {
             anyhow::bail!("Backend '{}' referenced in route not found", route.backend);
         }
+        
+        if let Some(pattern) = &route.client_cert_subject {
+            Regex::new(pattern)
+                .with_context(|| format!("Invalid client_cert_subject for route '{}'", route.path))?;
+        }
+        
+        validate_error_pages(&route.error_pages)
+            .with_context(|| format!("Invalid error page for route '{}'", route.path))?;
+        
+        if let Some(rate_limit) = &route.rate_limit {
+            validate_rate_limit(rate_limit)
+                .with_context(|| format!("Invalid rate_limit for route '{}'", route.path))?;
+        }
+        
+        if let Some(retry) = &route.retry {
+            validate_retry_config(retry)
+                .with_context(|| format!("Invalid retry settings for route '{}'", route.path))?;
+        }
+        
+        if route.hedge.as_ref().is_some_and(|hedge| hedge.delay_ms == 0) {
+            anyhow::bail!("Hedge delay_ms for route '{}' must be at least 1", route.path);
+        }
+    }
+    
+    if config.server.is_none() && !config.routes.is_empty() {
+        anyhow::bail!("Top-level routes require a 'server' block, use route_groups for listeners");
+    }
+    
+    let listeners = config.listeners();
+    if listeners.is_empty() {
+        anyhow::bail!("No listeners configured, add a 'server' block or 'listeners'");
+    }
+    
+    let mut addresses = HashSet::new();
+    for listener in &listeners {
+        let name = listener.display_name();
+        
+        if !addresses.insert(listener.listen_addr) {
+            anyhow::bail!("Listen address {} is used by more than one listener", listener.listen_addr);
+        }
+        
+        validate_error_pages(&listener.error_pages)
+            .with_context(|| format!("Invalid error page for listener '{}'", name))?;
+        
+        if let Some(redirect) = &listener.redirect {
+            if listener.route_group.is_some() || !listener.routes.is_empty() {
+                anyhow::bail!("Redirect listener '{}' cannot have routes", name);
+            }
+            
+            if ![301, 302, 307, 308].contains(&redirect.status) {
+                anyhow::bail!("Listener '{}' has invalid redirect status {}", name, redirect.status);
+            }
+        }
+        
+        if let Some(group) = &listener.route_group {
+            if !listener.routes.is_empty() {
+                anyhow::bail!("Listener '{}' sets both routes and route_group", name);
+            }
+            
+            if !config.route_groups.contains_key(group) {
+                anyhow::bail!("Route group '{}' referenced by listener '{}' not found", group, name);
+            }
+        }
+        
+        // Ensure SNI certificates have distinct server names
+        if let Some(tls) = &listener.tls {
+            validate_tls_config(tls)?;
+        }
+        
+        let verifies_client_certs = listener.tls.as_ref()
+            .is_some_and(|tls| tls.client_ca_path.is_some());
+        
+        for route in config.listener_routes(listener) {
+            if route.client_cert_subject.is_some() && !verifies_client_certs {
+                anyhow::bail!(
+                    "Route '{}' requires a client certificate but listener '{}' has no client_ca_path",
+                    route.path, name
+                );
+            }
+        }
+    }
+    
+    if let Some(rate_limit) = &config.rate_limit {
+        validate_rate_limit(rate_limit).context("Invalid global rate_limit")?;
+    }
+    
+    if let Some(status) = config.error_responses.templates.keys().find(|status| !(400..=599).contains(*status)) {
+        anyhow::bail!("Error template for status {} must be for a 4xx or 5xx status", status);
     }
     
     // Ensure backends have at least one server
@@ -122,7 +899,237 @@ fn validate_config(config: &Config) -> Result<()> {
         if backend.servers.is_empty() {
             anyhow::bail!("Backend '{}' has no servers", name);
         }
+        
+        if backend.servers.iter().all(|server| server.backup) {
+            anyhow::bail!("Backend '{}' has only backup servers", name);
+        }
+        
+        if let Some(server) = backend.servers.iter().find(|server| !(1..=MAX_WEIGHT).contains(&server.weight)) {
+            anyhow::bail!("Server '{}' in backend '{}' must have a weight from 1 to {}", server.url, name, MAX_WEIGHT);
+        }
+        
+        match backend.load_balancing.parse::<LoadBalancingStrategy>() {
+            Ok(LoadBalancingStrategy::ConsistentHash) => {}
+            Ok(_) if backend.hash_key.is_some() => {
+                anyhow::bail!("Backend '{}' sets hash_key without the consistent-hash strategy", name);
+            }
+            Ok(_) => {}
+            Err(e) => anyhow::bail!("Backend '{}' has {}", name, e),
+        }
+        
+        if let Some(health_check) = &backend.health_check {
+            if !health_check.path.starts_with('/') {
+                anyhow::bail!("Backend '{}' health check path must start with '/'", name);
+            }
+            
+            if health_check.interval == 0 || health_check.timeout == 0 {
+                anyhow::bail!("Backend '{}' health check interval and timeout must be at least 1 second", name);
+            }
+            
+            if health_check.timeout > health_check.interval {
+                anyhow::bail!("Backend '{}' health check timeout must not be longer than its interval", name);
+            }
+            
+            if health_check.healthy_threshold == 0 || health_check.unhealthy_threshold == 0 {
+                anyhow::bail!("Backend '{}' health check thresholds must be at least 1", name);
+            }
+            
+            if health_check.check_type == HealthCheckType::Http {
+                validate_http_health_check(name, health_check)?;
+            }
+        }
+        
+        if let Some(outlier) = &backend.outlier_detection {
+            if outlier.consecutive_failures == 0 {
+                anyhow::bail!("Backend '{}' outlier detection consecutive_failures must be at least 1", name);
+            }
+            
+            if outlier.base_ejection_time == 0 || outlier.max_ejection_time < outlier.base_ejection_time {
+                anyhow::bail!(
+                    "Backend '{}' outlier detection needs 1 <= base_ejection_time <= max_ejection_time",
+                    name
+                );
+            }
+            
+            if outlier.max_ejection_percent > 100 {
+                anyhow::bail!("Backend '{}' outlier detection max_ejection_percent must be at most 100", name);
+            }
+        }
+        
+        let breaker = &backend.circuit_breaker;
+        if breaker.failure_threshold == 0 || breaker.half_open_max_requests == 0 {
+            anyhow::bail!("Backend '{}' circuit breaker thresholds must be at least 1", name);
+        }
+        
+        if breaker.reset_timeout == 0 || breaker.half_open_timeout == 0 || breaker.window == 0 {
+            anyhow::bail!("Backend '{}' circuit breaker timeouts and window must be at least 1 second", name);
+        }
+        
+        if breaker.minimum_requests == 0 || !(1..=100).contains(&breaker.failure_rate_threshold) {
+            anyhow::bail!(
+                "Backend '{}' circuit breaker needs minimum_requests >= 1 and failure_rate_threshold between 1 and 100",
+                name
+            );
+        }
+        
+        if let Some(status) = breaker.failure_status_codes.iter().find(|status| !(100..=599).contains(*status)) {
+            anyhow::bail!("Backend '{}' circuit breaker has invalid failure status code {}", name, status);
+        }
+        
+        if let Some(hash_key) = &backend.hash_key {
+            let needs_name = matches!(hash_key.source, HashKeySource::Header | HashKeySource::Cookie);
+            if needs_name && hash_key.name.is_none() {
+                anyhow::bail!("Backend '{}' hash_key needs a name for {:?}", name, hash_key.source);
+            }
+        }
+        
+        if let Some(tls) = &backend.tls {
+            if let Some(server_name) = &tls.server_name {
+                if rustls::ServerName::try_from(server_name.as_str()).is_err() {
+                    anyhow::bail!("Backend '{}' has invalid TLS server_name '{}'", name, server_name);
+                }
+            }
+            
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                anyhow::bail!("Backend '{}' must set both client_cert and client_key", name);
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+fn validate_rate_limit(rate_limit: &RateLimitConfig) -> Result<()> {
+    if rate_limit.limit == 0 {
+        anyhow::bail!("limit must be at least 1");
+    }
+    
+    if rate_limit.window == 0 {
+        anyhow::bail!("window must be at least 1 second");
+    }
+    
+    if rate_limit.max_keys == 0 {
+        anyhow::bail!("max_keys must be at least 1");
+    }
+    
+    if rate_limit.key.is_empty() {
+        anyhow::bail!("key needs at least one part");
+    }
+    
+    for part in &rate_limit.key {
+        let needs_name = matches!(part.source, RateLimitKeySource::Header | RateLimitKeySource::JwtClaim);
+        if needs_name && part.name.is_none() {
+            anyhow::bail!("key part {:?} needs a name", part.source);
+        }
+        
+        if let Some(template) = &part.template {
+            if part.source != RateLimitKeySource::Path {
+                anyhow::bail!("key part {:?} can't have a template", part.source);
+            }
+            if !template.starts_with('/') {
+                anyhow::bail!("path template '{}' must start with '/'", template);
+            }
+        }
+    }
+    
+    for cidr in &rate_limit.allow_list {
+        parse_cidr(cidr).map_err(|e| anyhow::anyhow!("allow_list entry '{}': {}", cidr, e))?;
+    }
+    
+    Ok(())
+}
+
+fn validate_retry_config(retry: &RetryConfig) -> Result<()> {
+    if let Some(status) = retry.statuses.iter().find(|status| !(100..=599).contains(*status)) {
+        anyhow::bail!("{} is not a valid status code", status);
+    }
+    
+    if retry.backoff_base_ms > retry.backoff_max_ms {
+        anyhow::bail!("backoff_base_ms must not exceed backoff_max_ms");
+    }
+    
+    if !(0.0..=1.0).contains(&retry.budget_ratio) {
+        anyhow::bail!("budget_ratio must be between 0 and 1");
+    }
+    
+    Ok(())
+}
+
+fn validate_error_pages(pages: &[ErrorPageConfig]) -> Result<()> {
+    let mut statuses = HashSet::new();
+    
+    for page in pages {
+        if !(400..=599).contains(&page.status) {
+            anyhow::bail!("status {} is not a 4xx or 5xx status", page.status);
+        }
+        
+        if !statuses.insert(page.status) {
+            anyhow::bail!("status {} has more than one page", page.status);
+        }
+        
+        if page.file.is_some() == page.body.is_some() {
+            anyhow::bail!("page for status {} needs exactly one of file or body", page.status);
+        }
+        
+        if let Some(content_type) = &page.content_type {
+            if HeaderValue::from_str(content_type).is_err() {
+                anyhow::bail!("page for status {} has invalid content type '{}'", page.status, content_type);
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+fn validate_http_health_check(backend: &str, health_check: &HealthCheckConfig) -> Result<()> {
+    if Method::from_bytes(health_check.method.as_bytes()).is_err() {
+        anyhow::bail!("Backend '{}' has invalid health check method '{}'", backend, health_check.method);
+    }
+    
+    for (header, value) in &health_check.headers {
+        if HeaderName::from_bytes(header.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+            anyhow::bail!("Backend '{}' has invalid health check header '{}'", backend, header);
+        }
+    }
+    
+    if let Some(host) = &health_check.host {
+        if HeaderValue::from_str(host).is_err() {
+            anyhow::bail!("Backend '{}' has invalid health check host '{}'", backend, host);
+        }
+    }
+    
+    parse_status_range(&health_check.expected_status)
+        .map_err(|e| anyhow::anyhow!("Backend '{}' health check {}", backend, e))?;
+    
+    if let Some(pattern) = &health_check.body_regex {
+        Regex::new(pattern)
+            .with_context(|| format!("Invalid health check body_regex for backend '{}'", backend))?;
+    }
+    
+    Ok(())
+}
+
+fn validate_tls_config(tls: &TlsConfig) -> Result<()> {
+    let mut server_names = HashSet::new();
+    
+    for certificate in &tls.certificates {
+        let server_name = certificate.server_name.to_ascii_lowercase();
+        
+        if server_name.is_empty() || server_name == "*." {
+            anyhow::bail!("TLS certificate {} has an empty server_name", certificate.cert_path);
+        }
+        
+        if !server_names.insert(server_name) {
+            anyhow::bail!("Duplicate TLS certificate for server name '{}'", certificate.server_name);
+        }
+    }
+    
+    for header in [&tls.client_cert_header, &tls.client_cert_san_header] {
+        if HeaderName::from_bytes(header.as_bytes()).is_err() {
+            anyhow::bail!("Invalid client certificate header name '{}'", header);
+        }
     }
     
     Ok(())
-} 
+}
+
@@ -1,4 +1,7 @@
 use std::io;
+use std::time::Duration;
+
+use hyper::StatusCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,6 +12,9 @@ pub enum ProxyError {
     #[error("HTTP error: {0}")]
     HttpError(#[from] hyper::Error),
     
+    #[error("Failed to read request body: {0}")]
+    RequestBodyError(hyper::Error),
+    
     #[error("TLS error: {0}")]
     TlsError(String),
     
@@ -28,10 +34,31 @@ pub enum ProxyError {
     TimeoutError(String),
 
     #[error("Rate limit exceeded")]
-    RateLimitExceeded,
+    RateLimitExceeded { retry_after: Duration },
 
     #[error("Circuit breaker is open")]
     CircuitBreakerOpen,
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+}
+
+impl ProxyError {
+    /// Status code sent to the client for this error
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProxyError::RouteNotFound(_) => StatusCode::NOT_FOUND,
+            // The client broke off or sent a malformed body
+            ProxyError::RequestBodyError(_) => StatusCode::BAD_REQUEST,
+            ProxyError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ProxyError::RateLimitExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::CircuitBreakerOpen | ProxyError::NoHealthyBackends => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::TimeoutError(_) => StatusCode::GATEWAY_TIMEOUT,
+            // Only the upstream client produces hyper errors while proxying
+            ProxyError::HttpError(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl From<rustls::Error> for ProxyError {
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+
+use crate::config::{ErrorFormat, ErrorPageConfig, ErrorResponseConfig};
+use crate::error::{ProxyError, ProxyResult};
+use crate::features::ratelimit::ceil_secs;
+
+const TEXT: &str = "text/plain; charset=utf-8";
+const HTML: &str = "text/html; charset=utf-8";
+const JSON: &str = "application/json";
+
+/// Body for one status, `{{status}}` and `{{reason}}` are filled in when served
+pub struct ErrorPage {
+    body: String,
+    content_type: String,
+}
+
+impl ErrorPage {
+    fn from_file(path: &str, content_type: Option<&str>) -> ProxyResult<Self> {
+        let body = fs::read_to_string(path)
+            .map_err(|e| ProxyError::ConfigError(format!("Failed to read error page {}: {}", path, e)))?;
+
+        Ok(ErrorPage {
+            body,
+            content_type: content_type.unwrap_or_else(|| content_type_for(path)).to_string(),
+        })
+    }
+
+    fn render(&self, status: StatusCode) -> Response<Body> {
+        let body = self.body
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or("Error"));
+
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, self.content_type.as_str())
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+/// Error pages configured on a route or listener
+#[derive(Default)]
+pub struct ErrorPageSet {
+    pages: HashMap<StatusCode, ErrorPage>,
+}
+
+impl ErrorPageSet {
+    pub fn new(configs: &[ErrorPageConfig]) -> ProxyResult<Self> {
+        let mut pages = HashMap::new();
+
+        for config in configs {
+            let page = match (&config.file, &config.body) {
+                (Some(path), _) => ErrorPage::from_file(path, config.content_type.as_deref())?,
+                (None, body) => ErrorPage {
+                    body: body.clone().unwrap_or_default(),
+                    content_type: config.content_type.clone().unwrap_or_else(|| TEXT.to_string()),
+                },
+            };
+
+            pages.insert(parse_status(config.status)?, page);
+        }
+
+        Ok(ErrorPageSet { pages })
+    }
+
+    fn get(&self, status: StatusCode) -> Option<&ErrorPage> {
+        self.pages.get(&status)
+    }
+}
+
+/// Renders the responses for errors the proxy generates itself. Bodies only
+/// carry the status, internal error details stay in the logs
+pub struct ErrorPages {
+    format: ErrorFormat,
+    templates: ErrorPageSet,
+}
+
+impl ErrorPages {
+    pub fn new(config: &ErrorResponseConfig) -> ProxyResult<Self> {
+        let mut pages = HashMap::new();
+
+        for (status, path) in &config.templates {
+            pages.insert(parse_status(*status)?, ErrorPage::from_file(path, None)?);
+        }
+
+        Ok(ErrorPages {
+            format: config.format,
+            templates: ErrorPageSet { pages },
+        })
+    }
+
+    /// Response for a proxy error, using the first of `overrides` that has a
+    /// page for its status before the global templates
+    pub fn render(&self, error: &ProxyError, overrides: &[&ErrorPageSet]) -> Response<Body> {
+        let status = error.status_code();
+
+        let mut response = match self.find(status, overrides) {
+            Some(page) => page.render(status),
+            None => self.render_default(status),
+        };
+
+        if let ProxyError::RateLimitExceeded { retry_after } = error {
+            response.headers_mut().insert(RETRY_AFTER, ceil_secs(*retry_after).max(1).into());
+        }
+
+        response
+    }
+
+    /// Swap the body of an upstream 5xx for the matching error page. Responses
+    /// without a page are passed through untouched
+    pub fn intercept(&self, response: Response<Body>, overrides: &[&ErrorPageSet]) -> Response<Body> {
+        let status = response.status();
+        if !status.is_server_error() {
+            return response;
+        }
+
+        let Some(page) = self.find(status, overrides) else {
+            return response;
+        };
+
+        let mut intercepted = page.render(status);
+        if let Some(retry_after) = response.headers().get(RETRY_AFTER) {
+            intercepted.headers_mut().insert(RETRY_AFTER, retry_after.clone());
+        }
+
+        intercepted
+    }
+
+    fn find<'a>(&'a self, status: StatusCode, overrides: &[&'a ErrorPageSet]) -> Option<&'a ErrorPage> {
+        overrides.iter()
+            .chain(std::iter::once(&&self.templates))
+            .find_map(|pages| pages.get(status))
+    }
+
+    fn render_default(&self, status: StatusCode) -> Response<Body> {
+        let reason = status.canonical_reason().unwrap_or("Error");
+
+        let (content_type, body) = match self.format {
+            ErrorFormat::Text => (TEXT, format!("{} {}\n", status.as_str(), reason)),
+            ErrorFormat::Json => {
+                let body = serde_json::json!({ "status": status.as_u16(), "error": reason });
+                (JSON, body.to_string())
+            }
+        };
+
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+fn parse_status(status: u16) -> ProxyResult<StatusCode> {
+    StatusCode::from_u16(status)
+        .map_err(|_| ProxyError::ConfigError(format!("Invalid error page status {}", status)))
+}
+
+fn content_type_for(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => HTML,
+        Some("json") => JSON,
+        _ => TEXT,
+    }
+}
@@ -4,6 +4,11 @@ use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tracing::{info, warn};
 
+use crate::config::{self, CircuitBreakerMode};
+
+// Number of buckets the rolling window is split into
+const WINDOW_BUCKETS: u64 = 10;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum CircuitState {
     Closed,    // Normal operation
@@ -13,110 +18,437 @@ pub enum CircuitState {
 
 #[derive(Debug, Clone)]
 pub struct CircuitBreakerConfig {
+    pub mode: CircuitBreakerMode,
     pub failure_threshold: u32,
+    pub failure_rate_threshold: u32,
+    pub window: Duration,
+    pub minimum_requests: u32,
+    pub failure_status_codes: Vec<u16>,
     pub reset_timeout: Duration,
     pub half_open_timeout: Duration,
+    pub half_open_max_requests: u32,
+}
+
+impl From<&config::CircuitBreakerConfig> for CircuitBreakerConfig {
+    fn from(config: &config::CircuitBreakerConfig) -> Self {
+        CircuitBreakerConfig {
+            mode: config.mode,
+            failure_threshold: config.failure_threshold,
+            failure_rate_threshold: config.failure_rate_threshold,
+            window: Duration::from_secs(config.window),
+            minimum_requests: config.minimum_requests,
+            failure_status_codes: config.failure_status_codes.clone(),
+            reset_timeout: Duration::from_secs(config.reset_timeout),
+            half_open_timeout: Duration::from_secs(config.half_open_timeout),
+            half_open_max_requests: config.half_open_max_requests,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct WindowBucket {
+    epoch: u64,
+    successes: u32,
+    failures: u32,
+}
+
+/// Outcomes over the last `window`, counted in fixed time buckets so
+/// memory stays constant however many requests come through
+struct RollingWindow {
+    started: Instant,
+    bucket_width: Duration,
+    buckets: Vec<WindowBucket>,
+}
+
+impl RollingWindow {
+    fn new(window: Duration) -> Self {
+        RollingWindow {
+            started: Instant::now(),
+            bucket_width: (window / WINDOW_BUCKETS as u32).max(Duration::from_millis(1)),
+            buckets: vec![WindowBucket::default(); WINDOW_BUCKETS as usize],
+        }
+    }
+
+    fn epoch(&self) -> u64 {
+        (self.started.elapsed().as_nanos() / self.bucket_width.as_nanos()) as u64
+    }
+
+    fn record(&mut self, failure: bool) {
+        let epoch = self.epoch();
+        let bucket = &mut self.buckets[(epoch % WINDOW_BUCKETS) as usize];
+
+        if bucket.epoch != epoch {
+            *bucket = WindowBucket { epoch, ..WindowBucket::default() };
+        }
+
+        if failure {
+            bucket.failures += 1;
+        } else {
+            bucket.successes += 1;
+        }
+    }
+
+    /// Requests and failures in the window
+    fn totals(&self) -> (u32, u32) {
+        let epoch = self.epoch();
+
+        self.buckets.iter()
+            .filter(|bucket| bucket.epoch + WINDOW_BUCKETS > epoch)
+            .fold((0, 0), |(requests, failures), bucket| {
+                (requests + bucket.successes + bucket.failures, failures + bucket.failures)
+            })
+    }
+
+    fn reset(&mut self) {
+        self.buckets.fill(WindowBucket::default());
+    }
 }
 
 struct CircuitMetrics {
+    config: CircuitBreakerConfig,
     failures: u32,
+    window: RollingWindow,
     last_failure: Instant,
     state: CircuitState,
-    total_requests: u64,
-    successful_requests: u64,
-    failed_requests: u64,
+    half_open_since: Instant,
+    trial_requests: u32,
+    trial_successes: u32,
+}
+
+impl CircuitMetrics {
+    fn new(config: CircuitBreakerConfig) -> Self {
+        let now = Instant::now();
+        CircuitMetrics {
+            window: RollingWindow::new(config.window),
+            config,
+            failures: 0,
+            last_failure: now,
+            state: CircuitState::Closed,
+            half_open_since: now,
+            trial_requests: 0,
+            trial_successes: 0,
+        }
+    }
+
+    fn open(&mut self, backend: &str) {
+        warn!("Circuit opened for backend: {}", backend);
+        self.state = CircuitState::Open;
+        self.last_failure = Instant::now();
+        self.window.reset();
+    }
+
+    fn half_open(&mut self, backend: &str) {
+        info!("Circuit moving to half-open state for backend: {}", backend);
+        self.state = CircuitState::HalfOpen;
+        self.half_open_since = Instant::now();
+        self.trial_requests = 0;
+        self.trial_successes = 0;
+    }
+
+    fn close(&mut self, backend: &str) {
+        info!("Circuit closed for backend: {}", backend);
+        self.state = CircuitState::Closed;
+        self.failures = 0;
+        self.window.reset();
+    }
+
+    fn on_success(&mut self, backend: &str) {
+        self.window.record(false);
+
+        match self.state {
+            CircuitState::Closed => self.failures = 0,
+            CircuitState::HalfOpen => {
+                self.trial_successes += 1;
+                if self.trial_successes >= self.config.half_open_max_requests {
+                    self.close(backend);
+                }
+            }
+            CircuitState::Open => {}
+        }
+    }
+
+    fn on_failure(&mut self, backend: &str) {
+        self.failures += 1;
+        self.last_failure = Instant::now();
+        self.window.record(true);
+
+        match self.state {
+            // A single failed trial is enough to stay open
+            CircuitState::HalfOpen => self.open(backend),
+            CircuitState::Closed if self.should_trip() => self.open(backend),
+            _ => {}
+        }
+    }
+
+    fn should_trip(&self) -> bool {
+        match self.config.mode {
+            CircuitBreakerMode::Consecutive => self.failures >= self.config.failure_threshold,
+            CircuitBreakerMode::ErrorRate => {
+                let (requests, failures) = self.window.totals();
+                requests >= self.config.minimum_requests
+                    && failures * 100 >= self.config.failure_rate_threshold * requests
+            }
+        }
+    }
 }
 
+/// Circuit state for every backend, each with its own settings
 pub struct CircuitBreaker {
-    config: CircuitBreakerConfig,
     metrics: Arc<RwLock<HashMap<String, CircuitMetrics>>>,
 }
 
 impl CircuitBreaker {
-    pub fn new(config: CircuitBreakerConfig) -> Self {
+    pub fn new(configs: HashMap<String, CircuitBreakerConfig>) -> Self {
+        let metrics = configs.into_iter()
+            .map(|(backend, config)| (backend, CircuitMetrics::new(config)))
+            .collect();
+
         CircuitBreaker {
-            config,
-            metrics: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Arc::new(RwLock::new(metrics)),
         }
     }
 
     pub async fn pre_request(&self, backend: &str) -> bool {
         let mut metrics = self.metrics.write().await;
-        let metric = metrics.entry(backend.to_string()).or_insert(CircuitMetrics {
-            failures: 0,
-            last_failure: Instant::now(),
-            state: CircuitState::Closed,
-            total_requests: 0,
-            successful_requests: 0,
-            failed_requests: 0,
-        });
+        let Some(metric) = metrics.get_mut(backend) else {
+            return true;
+        };
 
-        metric.total_requests += 1;
+        if metric.state == CircuitState::Open
+            && Instant::now().duration_since(metric.last_failure) > metric.config.reset_timeout
+        {
+            metric.half_open(backend);
+        }
 
         match metric.state {
             CircuitState::Closed => true,
-            CircuitState::Open => {
-                if Instant::now().duration_since(metric.last_failure) > self.config.reset_timeout {
-                    info!("Circuit moving to half-open state for backend: {}", backend);
-                    metric.state = CircuitState::HalfOpen;
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                // Trials that never report back (e.g. cancelled by the client) must not
+                // leave the circuit stuck half-open, so give up on them after a while
+                if Instant::now().duration_since(metric.half_open_since) > metric.config.half_open_timeout {
+                    warn!("Trial requests for backend {} did not succeed in time", backend);
+                    metric.open(backend);
+                    return false;
+                }
+
+                if metric.trial_requests < metric.config.half_open_max_requests {
+                    metric.trial_requests += 1;
                     true
                 } else {
                     false
                 }
             }
-            CircuitState::HalfOpen => true,
         }
     }
 
-    pub async fn record_success(&self, backend: &str) {
+    /// Whether the backend's circuit is closed, so it takes extra attempts like
+    /// retries and hedges. Unlike `pre_request` this takes no trial slot
+    pub async fn is_closed(&self, backend: &str) -> bool {
+        let metrics = self.metrics.read().await;
+        metrics.get(backend).is_none_or(|metric| metric.state == CircuitState::Closed)
+    }
+
+    pub async fn record_failure(&self, backend: &str) {
         let mut metrics = self.metrics.write().await;
         if let Some(metric) = metrics.get_mut(backend) {
-            metric.successful_requests += 1;
-            if metric.state == CircuitState::HalfOpen {
-                info!("Circuit closed for backend: {}", backend);
-                metric.state = CircuitState::Closed;
-                metric.failures = 0;
-            }
+            metric.on_failure(backend);
         }
     }
 
-    pub async fn record_failure(&self, backend: &str) {
+    /// Record a response, which fails if its status is one of the backend's failure status codes
+    pub async fn record_response(&self, backend: &str, status: u16) {
         let mut metrics = self.metrics.write().await;
         if let Some(metric) = metrics.get_mut(backend) {
-            metric.failed_requests += 1;
-            metric.failures += 1;
-            metric.last_failure = Instant::now();
-
-            if metric.failures >= self.config.failure_threshold {
-                warn!("Circuit opened for backend: {}", backend);
-                metric.state = CircuitState::Open;
+            if metric.config.failure_status_codes.contains(&status) {
+                metric.on_failure(backend);
+            } else {
+                metric.on_success(backend);
             }
         }
     }
+}
 
-    pub async fn get_metrics(&self) -> HashMap<String, CircuitBreakerMetrics> {
-        let metrics = self.metrics.read().await;
-        let mut result = HashMap::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for (backend, metric) in metrics.iter() {
-            result.insert(backend.clone(), CircuitBreakerMetrics {
-                state: metric.state.clone(),
-                total_requests: metric.total_requests,
-                successful_requests: metric.successful_requests,
-                failed_requests: metric.failed_requests,
-                current_failures: metric.failures,
-            });
+    fn config(mode: CircuitBreakerMode) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            mode,
+            failure_threshold: 2,
+            failure_rate_threshold: 50,
+            window: Duration::from_secs(1),
+            minimum_requests: 4,
+            failure_status_codes: Vec::new(),
+            reset_timeout: Duration::from_secs(30),
+            half_open_timeout: Duration::from_secs(10),
+            half_open_max_requests: 2,
         }
+    }
 
-        result
+    // Move the window's clock forward without sleeping
+    fn advance(window: &mut RollingWindow, by: Duration) {
+        window.started = window.started.checked_sub(by).unwrap();
     }
-}
 
-#[derive(Debug)]
-pub struct CircuitBreakerMetrics {
-    pub state: CircuitState,
-    pub total_requests: u64,
-    pub successful_requests: u64,
-    pub failed_requests: u64,
-    pub current_failures: u32,
-} 
+    #[test]
+    fn stale_buckets_drop_out_of_the_window() {
+        // Ten buckets of 100ms
+        let mut window = RollingWindow::new(Duration::from_secs(1));
+        window.record(true);
+        window.record(false);
+        assert_eq!(window.totals(), (2, 1));
+
+        advance(&mut window, Duration::from_millis(500));
+        window.record(false);
+        assert_eq!(window.totals(), (3, 1));
+
+        // The first bucket is a full window old now
+        advance(&mut window, Duration::from_millis(500));
+        assert_eq!(window.totals(), (1, 0));
+
+        // Its slot is reused without its old counts
+        window.record(true);
+        assert_eq!(window.totals(), (2, 1));
+
+        advance(&mut window, Duration::from_secs(5));
+        assert_eq!(window.totals(), (0, 0));
+    }
+
+    #[test]
+    fn reset_empties_the_window() {
+        let mut window = RollingWindow::new(Duration::from_secs(1));
+        window.record(true);
+        window.reset();
+        assert_eq!(window.totals(), (0, 0));
+    }
+
+    #[test]
+    fn error_rate_waits_for_minimum_requests() {
+        let mut metrics = CircuitMetrics::new(config(CircuitBreakerMode::ErrorRate));
+
+        for _ in 0..3 {
+            metrics.on_failure("api");
+            assert_eq!(metrics.state, CircuitState::Closed);
+        }
+
+        metrics.on_failure("api");
+        assert_eq!(metrics.state, CircuitState::Open);
+    }
+
+    #[test]
+    fn error_rate_trips_at_the_threshold() {
+        let mut metrics = CircuitMetrics::new(config(CircuitBreakerMode::ErrorRate));
+
+        for _ in 0..3 {
+            metrics.on_success("api");
+        }
+
+        // 1 of 4, then 2 of 5, are below 50%
+        metrics.on_failure("api");
+        metrics.on_failure("api");
+        assert_eq!(metrics.state, CircuitState::Closed);
+
+        // 3 of 6 is exactly 50%
+        metrics.on_failure("api");
+        assert_eq!(metrics.state, CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn only_failure_status_codes_count_as_failures() {
+        let mut config = config(CircuitBreakerMode::Consecutive);
+        config.failure_status_codes = vec![503];
+        let breaker = CircuitBreaker::new(HashMap::from([("api".to_string(), config)]));
+
+        // A 500 isn't a failure here, so it breaks the run of 503s
+        breaker.record_response("api", 503).await;
+        breaker.record_response("api", 500).await;
+        breaker.record_response("api", 503).await;
+        assert!(breaker.is_closed("api").await);
+
+        breaker.record_response("api", 503).await;
+        assert!(!breaker.is_closed("api").await);
+
+        // Backends without a circuit breaker are always closed
+        breaker.record_response("other", 503).await;
+        assert!(breaker.is_closed("other").await);
+    }
+
+    async fn tripped_breaker() -> CircuitBreaker {
+        let config = config(CircuitBreakerMode::Consecutive);
+        let breaker = CircuitBreaker::new(HashMap::from([("api".to_string(), config)]));
+        breaker.record_failure("api").await;
+        breaker.record_failure("api").await;
+        assert!(!breaker.pre_request("api").await);
+        breaker
+    }
+
+    // Move the circuit's clocks back as if `by` had passed
+    async fn advance_circuit(breaker: &CircuitBreaker, by: Duration) {
+        let mut metrics = breaker.metrics.write().await;
+        let metric = metrics.get_mut("api").unwrap();
+        metric.last_failure = metric.last_failure.checked_sub(by).unwrap();
+        metric.half_open_since = metric.half_open_since.checked_sub(by).unwrap();
+    }
+
+    async fn state(breaker: &CircuitBreaker) -> CircuitState {
+        breaker.metrics.read().await["api"].state.clone()
+    }
+
+    #[tokio::test]
+    async fn half_open_admits_a_limited_number_of_trials() {
+        let breaker = tripped_breaker().await;
+        advance_circuit(&breaker, Duration::from_secs(31)).await;
+
+        assert!(breaker.pre_request("api").await);
+        assert_eq!(state(&breaker).await, CircuitState::HalfOpen);
+        assert!(breaker.pre_request("api").await);
+        assert!(!breaker.pre_request("api").await);
+        assert!(!breaker.is_closed("api").await);
+    }
+
+    #[tokio::test]
+    async fn half_open_closes_after_enough_successful_trials() {
+        let breaker = tripped_breaker().await;
+        advance_circuit(&breaker, Duration::from_secs(31)).await;
+        assert!(breaker.pre_request("api").await);
+        assert!(breaker.pre_request("api").await);
+
+        breaker.record_response("api", 200).await;
+        assert_eq!(state(&breaker).await, CircuitState::HalfOpen);
+
+        breaker.record_response("api", 200).await;
+        assert_eq!(state(&breaker).await, CircuitState::Closed);
+        assert!(breaker.pre_request("api").await);
+    }
+
+    #[tokio::test]
+    async fn a_failed_trial_reopens_the_circuit() {
+        let breaker = tripped_breaker().await;
+        advance_circuit(&breaker, Duration::from_secs(31)).await;
+        assert!(breaker.pre_request("api").await);
+
+        breaker.record_failure("api").await;
+        assert_eq!(state(&breaker).await, CircuitState::Open);
+        assert!(!breaker.pre_request("api").await);
+    }
+
+    #[tokio::test]
+    async fn half_open_reopens_when_trials_never_report_back() {
+        let breaker = tripped_breaker().await;
+        advance_circuit(&breaker, Duration::from_secs(31)).await;
+        assert!(breaker.pre_request("api").await);
+        assert!(breaker.pre_request("api").await);
+
+        advance_circuit(&breaker, Duration::from_secs(11)).await;
+        assert!(!breaker.pre_request("api").await);
+        assert_eq!(state(&breaker).await, CircuitState::Open);
+
+        // The reset timeout starts over from the reopening
+        advance_circuit(&breaker, Duration::from_secs(29)).await;
+        assert!(!breaker.pre_request("api").await);
+        advance_circuit(&breaker, Duration::from_secs(2)).await;
+        assert!(breaker.pre_request("api").await);
+    }
+}
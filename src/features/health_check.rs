@@ -0,0 +1,249 @@
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, HOST, USER_AGENT};
+use hyper::{Body, Method, Request, Uri};
+use regex::Regex;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::config::{HealthCheckConfig, HealthCheckType};
+use crate::error::{ProxyError, ProxyResult};
+use crate::features::load_balancer::ServerState;
+use crate::proxy::HttpClient;
+
+// Bodies longer than this are cut off before matching
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ProbeCounts {
+    consecutive_successes: u32,
+    consecutive_failures: u32,
+}
+
+/// Periodically probes every server of a backend and publishes up/down state
+pub struct HealthChecker {
+    backend: String,
+    config: HealthCheckConfig,
+    method: Method,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    expected_status: RangeInclusive<u16>,
+    body_regex: Option<Regex>,
+    servers: Vec<Arc<ServerState>>,
+    client: HttpClient,
+}
+
+impl HealthChecker {
+    pub fn new(backend: String, config: HealthCheckConfig, servers: Vec<Arc<ServerState>>, client: HttpClient) -> ProxyResult<Self> {
+        let invalid = |what: &str| ProxyError::ConfigError(format!("Invalid health check {} for backend '{}'", what, backend));
+
+        let method = Method::from_bytes(config.method.as_bytes()).map_err(|_| invalid("method"))?;
+
+        let mut headers = Vec::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid("header"))?;
+            let value = HeaderValue::from_str(value).map_err(|_| invalid("header"))?;
+            headers.push((name, value));
+        }
+
+        if let Some(host) = &config.host {
+            headers.push((HOST, HeaderValue::from_str(host).map_err(|_| invalid("host"))?));
+        }
+
+        let expected_status = parse_status_range(&config.expected_status).map_err(|_| invalid("expected_status"))?;
+        let body_regex = config.body_regex.as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|_| invalid("body_regex"))?;
+
+        Ok(HealthChecker {
+            backend,
+            config,
+            method,
+            headers,
+            expected_status,
+            body_regex,
+            servers,
+            client,
+        })
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    async fn run(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval));
+        // A round that overruns shouldn't be followed by a burst of catch-up rounds
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut counts = vec![ProbeCounts::default(); self.servers.len()];
+
+        match self.config.check_type {
+            HealthCheckType::Http => info!(
+                "Health checking backend {} every {}s with {} {}",
+                self.backend, self.config.interval, self.method, self.config.path
+            ),
+            HealthCheckType::Tcp => info!(
+                "Health checking backend {} every {}s with TCP connects",
+                self.backend, self.config.interval
+            ),
+        }
+
+        loop {
+            interval.tick().await;
+
+            // Probe all servers at once so one slow server doesn't delay the rest
+            let results = join_all(self.servers.iter().map(|server| self.probe(server))).await;
+
+            for ((server, count), healthy) in self.servers.iter().zip(counts.iter_mut()).zip(results) {
+                self.update(server, count, healthy);
+            }
+        }
+    }
+
+    async fn probe(&self, server: &ServerState) -> bool {
+        let timeout = Duration::from_secs(self.config.timeout);
+        let check = async {
+            match self.config.check_type {
+                HealthCheckType::Http => self.check_http(server).await,
+                HealthCheckType::Tcp => self.check_tcp(server).await,
+            }
+        };
+
+        match tokio::time::timeout(timeout, check).await {
+            Ok(Ok(())) => true,
+            Ok(Err(reason)) => {
+                debug!("Health check for {} failed: {}", server.url, reason);
+                false
+            }
+            Err(_) => {
+                debug!("Health check for {} timed out after {}s", server.url, self.config.timeout);
+                false
+            }
+        }
+    }
+
+    async fn check_http(&self, server: &ServerState) -> Result<(), String> {
+        let uri = format!("{}{}", server.url.trim_end_matches('/'), self.config.path)
+            .parse::<Uri>()
+            .map_err(|e| format!("invalid URI: {}", e))?;
+
+        let mut request = Request::builder()
+            .method(self.method.clone())
+            .uri(uri)
+            .header(USER_AGENT, "ranx-health-check")
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+
+        for (name, value) in &self.headers {
+            request.headers_mut().insert(name.clone(), value.clone());
+        }
+
+        let response = self.client.request(request).await.map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+
+        if !self.expected_status.contains(&status) {
+            return Err(format!("unexpected status {}", status));
+        }
+
+        if self.config.body_contains.is_none() && self.body_regex.is_none() {
+            return Ok(());
+        }
+
+        let body = read_body(response.into_body()).await?;
+
+        if let Some(needle) = &self.config.body_contains {
+            if !body.contains(needle.as_str()) {
+                return Err(format!("body does not contain '{}'", needle));
+            }
+        }
+
+        if let Some(regex) = &self.body_regex {
+            if !regex.is_match(&body) {
+                return Err(format!("body does not match '{}'", regex));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_tcp(&self, server: &ServerState) -> Result<(), String> {
+        let uri = server.url.parse::<Uri>().map_err(|e| format!("invalid URI: {}", e))?;
+        let host = uri.host().ok_or("missing host")?.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+
+        TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn update(&self, server: &ServerState, count: &mut ProbeCounts, healthy: bool) {
+        if healthy {
+            count.consecutive_successes += 1;
+            count.consecutive_failures = 0;
+        } else {
+            count.consecutive_failures += 1;
+            count.consecutive_successes = 0;
+        }
+
+        if server.is_healthy() && count.consecutive_failures >= self.config.unhealthy_threshold {
+            warn!(
+                "Server {} in backend {} marked down after {} failed health checks",
+                server.url, self.backend, count.consecutive_failures
+            );
+            server.set_healthy(false);
+        } else if !server.is_healthy() && count.consecutive_successes >= self.config.healthy_threshold {
+            info!(
+                "Server {} in backend {} marked up after {} passed health checks",
+                server.url, self.backend, count.consecutive_successes
+            );
+            server.set_healthy(true);
+        }
+    }
+}
+
+/// Parse "200" or "200-299" into an inclusive range of status codes
+pub fn parse_status_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |code: &str| -> Result<u16, String> {
+        code.trim().parse::<u16>()
+            .ok()
+            .filter(|code| (100..=599).contains(code))
+            .ok_or_else(|| format!("status '{}' is not a valid status code", code.trim()))
+    };
+
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let code = parse(value)?;
+            (code, code)
+        }
+    };
+
+    if start > end {
+        return Err(format!("status range '{}' is empty", value));
+    }
+
+    Ok(start..=end)
+}
+
+// Collect the body as text, up to MAX_BODY_BYTES
+async fn read_body(mut body: Body) -> Result<String, String> {
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        let remaining = MAX_BODY_BYTES - bytes.len();
+        bytes.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+
+        if bytes.len() >= MAX_BODY_BYTES {
+            break;
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
@@ -0,0 +1,461 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::config::BackendServerConfig;
+use crate::features::outlier::OutlierState;
+
+// Points on the hash ring per unit of server weight
+const VIRTUAL_NODES_PER_WEIGHT: u32 = 160;
+
+/// Highest server weight, which keeps the hash ring at most 160k points per server
+pub const MAX_WEIGHT: u32 = 1000;
+
+// Weight of the newest sample in the latency moving average
+const EWMA_ALPHA: f64 = 0.3;
+
+// Without new samples the average fades towards zero with this time constant,
+// so a server that was slow gets probed again instead of being starved forever
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+// Lowest latency a server is scored with, so a fast or long idle server still
+// counts its in-flight requests
+const LATENCY_FLOOR: f64 = 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadBalancingStrategy {
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    ConsistentHash,
+    P2cEwma,
+}
+
+impl FromStr for LoadBalancingStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(LoadBalancingStrategy::RoundRobin),
+            "weighted-round-robin" => Ok(LoadBalancingStrategy::WeightedRoundRobin),
+            "least-connections" => Ok(LoadBalancingStrategy::LeastConnections),
+            "consistent-hash" => Ok(LoadBalancingStrategy::ConsistentHash),
+            "p2c-ewma" => Ok(LoadBalancingStrategy::P2cEwma),
+            _ => Err(format!("unknown load balancing strategy '{}'", s)),
+        }
+    }
+}
+
+/// Runtime state of a single backend server
+#[derive(Debug)]
+pub struct ServerState {
+    pub url: String,
+    pub weight: u32,
+    pub backup: bool,
+    healthy: AtomicBool,
+    in_flight: AtomicUsize,
+    ewma_latency: Mutex<Option<LatencyEwma>>,
+    outlier: Mutex<OutlierState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LatencyEwma {
+    seconds: f64,
+    updated: Instant,
+}
+
+impl LatencyEwma {
+    fn decayed(&self, now: Instant) -> f64 {
+        let idle = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.seconds * (-idle / EWMA_DECAY.as_secs_f64()).exp()
+    }
+}
+
+impl ServerState {
+    pub fn new(config: &BackendServerConfig) -> Self {
+        ServerState {
+            url: config.url.clone(),
+            weight: config.weight,
+            backup: config.backup,
+            healthy: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+            ewma_latency: Mutex::new(None),
+            outlier: Mutex::new(OutlierState::default()),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Updated by active health checking
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Temporarily out of rotation after failing real traffic
+    pub fn is_ejected(&self) -> bool {
+        self.outlier().is_ejected(Instant::now())
+    }
+
+    /// Passive health state, updated by outlier detection
+    pub fn outlier(&self) -> MutexGuard<'_, OutlierState> {
+        self.outlier.lock().unwrap()
+    }
+
+    /// Number of requests whose response hasn't finished streaming
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Moving average of response latency in seconds, None before the first sample
+    pub fn ewma_latency(&self) -> Option<f64> {
+        self.ewma_latency.lock().unwrap()
+            .map(|ewma| ewma.decayed(Instant::now()))
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let now = Instant::now();
+        let sample = latency.as_secs_f64();
+        let mut ewma = self.ewma_latency.lock().unwrap();
+
+        let seconds = match *ewma {
+            Some(current) => EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * current.decayed(now),
+            None => sample,
+        };
+
+        *ewma = Some(LatencyEwma { seconds, updated: now });
+    }
+
+    // Lower is better: expected wait if this request queued behind the in-flight ones.
+    // Servers without samples are assumed to be as fast as `prior`
+    fn load_score(&self, prior: f64) -> f64 {
+        let latency = self.ewma_latency().unwrap_or(prior).max(LATENCY_FLOOR);
+        latency * (self.in_flight() + 1) as f64
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn start_request(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { server: self.clone() }
+    }
+}
+
+/// Decrements the server's in-flight count when dropped
+#[derive(Debug)]
+pub struct InFlightGuard {
+    server: Arc<ServerState>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.server.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct LoadBalancer {
+    strategy: LoadBalancingStrategy,
+    servers: Vec<Arc<ServerState>>,
+    next_index: AtomicUsize,
+    // Smooth weighted round-robin state, indexed like `servers`
+    current_weights: Mutex<Vec<i64>>,
+    // Consistent hash ring of (point, server index), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl LoadBalancer {
+    pub fn new(strategy: LoadBalancingStrategy, servers: &[BackendServerConfig]) -> Self {
+        let ring = match strategy {
+            LoadBalancingStrategy::ConsistentHash => build_ring(servers),
+            _ => Vec::new(),
+        };
+
+        LoadBalancer {
+            strategy,
+            servers: servers.iter().map(ServerState::new).map(Arc::new).collect(),
+            next_index: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; servers.len()]),
+            ring,
+        }
+    }
+
+    pub fn strategy(&self) -> LoadBalancingStrategy {
+        self.strategy
+    }
+
+    pub fn servers(&self) -> &[Arc<ServerState>] {
+        &self.servers
+    }
+
+    /// Pick a server, `hash_key` is only used by consistent-hash. Servers in
+    /// `exclude` are skipped unless no other server can take the request
+    pub fn select(&self, hash_key: Option<&[u8]>, exclude: &[Arc<ServerState>]) -> Option<Arc<ServerState>> {
+        let mut candidates = self.candidates();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let fresh: Vec<usize> = candidates.iter()
+            .copied()
+            .filter(|&index| !exclude.iter().any(|server| Arc::ptr_eq(server, &self.servers[index])))
+            .collect();
+        if !fresh.is_empty() {
+            candidates = fresh;
+        }
+
+        let index = match (self.strategy, hash_key) {
+            (LoadBalancingStrategy::RoundRobin, _) => self.select_round_robin(&candidates, !exclude.is_empty()),
+            (LoadBalancingStrategy::WeightedRoundRobin, _) => self.select_weighted(&candidates),
+            (LoadBalancingStrategy::LeastConnections, _) => self.select_least_connections(&candidates),
+            (LoadBalancingStrategy::ConsistentHash, Some(key)) => self.select_hashed(&candidates, key),
+            // Requests without a key have no affinity to keep
+            (LoadBalancingStrategy::ConsistentHash, None) => self.select_round_robin(&candidates, !exclude.is_empty()),
+            (LoadBalancingStrategy::P2cEwma, _) => self.select_p2c(&candidates),
+        };
+
+        Some(self.servers[index].clone())
+    }
+
+    /// Indexes of the healthy primaries, or of the healthy backups if no primary is up.
+    /// Ejected servers count as down
+    fn candidates(&self) -> Vec<usize> {
+        let healthy = |backup: bool| -> Vec<usize> {
+            self.servers.iter()
+                .enumerate()
+                .filter(|(_, server)| server.backup == backup && server.is_healthy() && !server.is_ejected())
+                .map(|(index, _)| index)
+                .collect()
+        };
+
+        let primaries = healthy(false);
+        if primaries.is_empty() {
+            healthy(true)
+        } else {
+            primaries
+        }
+    }
+
+    fn select_round_robin(&self, candidates: &[usize], repeat: bool) -> usize {
+        // Repeat picks for retries and hedges don't advance the rotation, or they
+        // would shift where the following requests land
+        let next = if repeat {
+            self.next_index.load(Ordering::Relaxed)
+        } else {
+            self.next_index.fetch_add(1, Ordering::Relaxed)
+        };
+        candidates[next % candidates.len()]
+    }
+
+    // Smooth weighted round-robin as used by nginx: every pick raises each
+    // candidate by its weight and lowers the winner by the total, which
+    // interleaves servers instead of sending bursts to the heaviest one
+    fn select_weighted(&self, candidates: &[usize]) -> usize {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best = candidates[0];
+
+        for &index in candidates {
+            let weight = self.servers[index].weight as i64;
+            current_weights[index] += weight;
+            total += weight;
+
+            if current_weights[index] > current_weights[best] {
+                best = index;
+            }
+        }
+
+        current_weights[best] -= total;
+        best
+    }
+
+    fn select_hashed(&self, candidates: &[usize], key: &[u8]) -> usize {
+        let hash = hash_bytes(key);
+        let start = self.ring.partition_point(|&(point, _)| point < hash);
+
+        // Walk clockwise from the key to the first server that can take traffic,
+        // so a down server only moves its own keys
+        (0..self.ring.len())
+            .map(|i| self.ring[(start + i) % self.ring.len()].1)
+            .find(|index| candidates.contains(index))
+            .unwrap_or(candidates[0])
+    }
+
+    // Power of two choices: compare two random servers rather than scanning all,
+    // which avoids every proxy herding onto the same momentarily-best server
+    fn select_p2c(&self, candidates: &[usize]) -> usize {
+        if candidates.len() == 1 {
+            return candidates[0];
+        }
+
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..candidates.len());
+        let mut second = rng.gen_range(0..candidates.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+
+        // New and recovering servers are scored like the average candidate, so a
+        // burst doesn't pile onto them before their first response
+        let sampled: Vec<f64> = candidates.iter()
+            .filter_map(|&index| self.servers[index].ewma_latency())
+            .collect();
+        let prior = match sampled.len() {
+            0 => LATENCY_FLOOR,
+            len => sampled.iter().sum::<f64>() / len as f64,
+        };
+
+        let (first, second) = (candidates[first], candidates[second]);
+        if self.servers[second].load_score(prior) < self.servers[first].load_score(prior) {
+            second
+        } else {
+            first
+        }
+    }
+
+    fn select_least_connections(&self, candidates: &[usize]) -> usize {
+        // Start the scan at a rotating offset so ties don't always go to the first server
+        let offset = self.next_index.fetch_add(1, Ordering::Relaxed);
+        let len = candidates.len();
+
+        (0..len)
+            .map(|i| candidates[(offset + i) % len])
+            .min_by_key(|&index| self.servers[index].in_flight())
+            .unwrap_or(candidates[0])
+    }
+}
+
+fn build_ring(servers: &[BackendServerConfig]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::new();
+
+    for (index, server) in servers.iter().enumerate() {
+        for node in 0..server.weight * VIRTUAL_NODES_PER_WEIGHT {
+            let point = hash_bytes(format!("{}#{}", server.url, node).as_bytes());
+            ring.push((point, index));
+        }
+    }
+
+    ring.sort_unstable();
+    ring
+}
+
+/// FNV-1a followed by the murmur3 finalizer, stable across processes and
+/// restarts unlike the std hasher, and well mixed for similar inputs
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(url: &str, weight: u32) -> BackendServerConfig {
+        BackendServerConfig { url: url.to_string(), weight, backup: false }
+    }
+
+    fn picks(balancer: &LoadBalancer, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| balancer.select(None, &[]).unwrap().url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn weighted_round_robin_interleaves_by_weight() {
+        let balancer = LoadBalancer::new(
+            LoadBalancingStrategy::WeightedRoundRobin,
+            &[server("a", 5), server("b", 1), server("c", 1)],
+        );
+
+        // The sequence nginx's smooth weighted round-robin produces for 5:1:1
+        let expected = ["a", "a", "b", "a", "c", "a", "a"];
+        assert_eq!(picks(&balancer, 14), [expected, expected].concat());
+    }
+
+    fn hashed_picks(servers: &[BackendServerConfig], keys: &[String]) -> Vec<String> {
+        let balancer = LoadBalancer::new(LoadBalancingStrategy::ConsistentHash, servers);
+        keys.iter()
+            .map(|key| balancer.select(Some(key.as_bytes()), &[]).unwrap().url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn ring_has_points_in_proportion_to_weight() {
+        let ring = build_ring(&[server("a", 1), server("b", 3)]);
+        let heavy = ring.iter().filter(|&&(_, index)| index == 1).count();
+
+        assert_eq!(ring.len(), 4 * VIRTUAL_NODES_PER_WEIGHT as usize);
+        assert_eq!(heavy, 3 * VIRTUAL_NODES_PER_WEIGHT as usize);
+        assert!(ring.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn adding_a_server_only_moves_keys_to_it() {
+        let keys: Vec<String> = (0..10_000).map(|i| format!("client-{}", i)).collect();
+        let mut servers: Vec<_> = ["a", "b", "c", "d"].iter().map(|url| server(url, 1)).collect();
+        let before = hashed_picks(&servers, &keys);
+
+        servers.push(server("e", 1));
+        let after = hashed_picks(&servers, &keys);
+
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(old, new)| old != new).collect();
+        assert!(moved.iter().all(|(_, new)| *new == "e"));
+
+        // Ideally a fifth of the keys move to the new server
+        let share = moved.len() as f64 / keys.len() as f64;
+        assert!((0.15..0.25).contains(&share), "{} of the keys moved", share);
+    }
+
+    #[test]
+    fn down_server_only_moves_its_own_keys() {
+        let keys: Vec<String> = (0..1_000).map(|i| format!("client-{}", i)).collect();
+        let servers = [server("a", 1), server("b", 1), server("c", 1)];
+        let balancer = LoadBalancer::new(LoadBalancingStrategy::ConsistentHash, &servers);
+        let pick = |key: &String| balancer.select(Some(key.as_bytes()), &[]).unwrap().url.clone();
+
+        let before: Vec<_> = keys.iter().map(pick).collect();
+        balancer.servers()[1].set_healthy(false);
+        let after: Vec<_> = keys.iter().map(pick).collect();
+
+        for (old, new) in before.iter().zip(&after) {
+            if old == "b" {
+                assert_ne!(new, "b");
+            } else {
+                assert_eq!(old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn p2c_counts_in_flight_requests_of_unsampled_servers() {
+        let balancer = LoadBalancer::new(LoadBalancingStrategy::P2cEwma, &[server("a", 1), server("b", 1)]);
+        let (sampled, fresh) = (&balancer.servers()[0], &balancer.servers()[1]);
+        sampled.record_latency(Duration::from_millis(10));
+
+        // Scored with the sampled server's latency, three in-flight requests make it the slower pick
+        let _busy: Vec<_> = (0..3).map(|_| fresh.start_request()).collect();
+        assert_eq!(picks(&balancer, 5), ["a"; 5]);
+    }
+
+    #[test]
+    fn weighted_round_robin_skips_unhealthy_servers() {
+        let balancer = LoadBalancer::new(
+            LoadBalancingStrategy::WeightedRoundRobin,
+            &[server("a", 2), server("b", 1)],
+        );
+        balancer.servers()[0].set_healthy(false);
+
+        assert_eq!(picks(&balancer, 3), ["b", "b", "b"]);
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tracing::info;
@@ -17,6 +18,8 @@ pub struct RequestMetrics {
 
 pub struct MetricsCollector {
     metrics: Arc<RwLock<HashMap<String, RequestMetrics>>>,
+    // Current values kept up to date by their owners, like tracked rate limit keys
+    gauges: Mutex<HashMap<String, Arc<AtomicU64>>>,
     start_time: Instant,
 }
 
@@ -24,10 +27,26 @@ impl MetricsCollector {
     pub fn new() -> Self {
         MetricsCollector {
             metrics: Arc::new(RwLock::new(HashMap::new())),
+            gauges: Mutex::new(HashMap::new()),
             start_time: Instant::now(),
         }
     }
 
+    /// The gauge called `name`, created at 0. Everyone asking for the same name shares it
+    pub fn gauge(&self, name: &str) -> Arc<AtomicU64> {
+        self.gauges.lock().unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn get_gauges(&self) -> HashMap<String, u64> {
+        self.gauges.lock().unwrap()
+            .iter()
+            .map(|(name, gauge)| (name.clone(), gauge.load(Ordering::Relaxed)))
+            .collect()
+    }
+
     pub async fn record_request(&self, backend: &str, start: Instant, status: u16, is_error: bool) {
         let duration = start.elapsed();
         let mut metrics = self.metrics.write().await;
@@ -93,19 +112,38 @@ impl MetricsCollector {
     pub async fn log_metrics(&self) {
         let metrics = self.get_metrics().await;
         for (backend, summary) in metrics {
+            let millis = |time: Option<Duration>| time.map_or(0.0, |time| time.as_secs_f64() * 1000.0);
+            let mut status_codes: Vec<_> = summary.status_code_distribution.into_iter().collect();
+            status_codes.sort();
+            let status_codes: Vec<_> = status_codes.iter()
+                .map(|(status, count)| format!("{}: {}", status, count))
+                .collect();
+
             info!(
                 "Backend {} metrics:\n\
                  - Uptime: {:.2} seconds\n\
-                 - Total requests: {}\n\
+                 - Total requests: {} ({} succeeded, {} failed)\n\
                  - Success rate: {:.2}%\n\
-                 - Avg response time: {:.2}ms",
+                 - Response time: {:.2}ms avg, {:.2}ms min, {:.2}ms max\n\
+                 - Status codes: {}",
                 backend,
                 summary.uptime.as_secs_f64(),
                 summary.total_requests,
+                summary.successful_requests,
+                summary.failed_requests,
                 summary.success_rate,
-                summary.avg_response_time * 1000.0
+                summary.avg_response_time * 1000.0,
+                millis(summary.min_response_time),
+                millis(summary.max_response_time),
+                status_codes.join(", ")
             );
         }
+
+        let mut gauges: Vec<_> = self.get_gauges().into_iter().collect();
+        gauges.sort();
+        for (name, value) in gauges {
+            info!("{} = {}", name, value);
+        }
     }
 }
 
@@ -1,36 +1,35 @@
 pub mod circuit_breaker;
+pub mod health_check;
+pub mod load_balancer;
 pub mod metrics;
+pub mod outlier;
 pub mod ratelimit;
+pub mod retry;
 
 use std::sync::Arc;
-use std::time::Duration;
+
+use crate::config::Config;
 
 pub struct Features {
-    pub rate_limiter: Arc<ratelimit::RateLimiter>,
+    /// Limiter for routes without their own policy, if a global one is configured
+    pub rate_limiter: Option<Arc<ratelimit::RateLimiter>>,
     pub circuit_breaker: Arc<circuit_breaker::CircuitBreaker>,
     pub metrics_collector: Arc<metrics::MetricsCollector>,
 }
 
 impl Features {
-    pub fn new() -> Self {
-        let rate_limiter = Arc::new(ratelimit::RateLimiter::new(
-            ratelimit::RateLimitConfig {
-                requests_per_second: 100,
-                burst_size: 50,
-                window_seconds: 60,
-            }
-        ));
+    pub fn new(config: &Config) -> Self {
+        let metrics_collector = Arc::new(metrics::MetricsCollector::new());
+
+        let rate_limiter = config.rate_limit.as_ref()
+            .map(|rate_limit| ratelimit::RateLimiter::new("global", rate_limit.into(), &metrics_collector));
 
         let circuit_breaker = Arc::new(circuit_breaker::CircuitBreaker::new(
-            circuit_breaker::CircuitBreakerConfig {
-                failure_threshold: 5,
-                reset_timeout: Duration::from_secs(30),
-                half_open_timeout: Duration::from_secs(5),
-            }
+            config.backends.iter()
+                .map(|(name, backend)| (name.clone(), (&backend.circuit_breaker).into()))
+                .collect()
         ));
 
-        let metrics_collector = Arc::new(metrics::MetricsCollector::new());
-
         Features {
             rate_limiter,
             circuit_breaker,
@@ -0,0 +1,194 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::config::OutlierDetectionConfig;
+use crate::features::load_balancer::ServerState;
+
+/// Passive health of a single server, kept on its `ServerState`
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    consecutive_failures: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierState {
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Ejects servers of a backend that keep failing real traffic
+pub struct OutlierDetector {
+    backend: String,
+    config: OutlierDetectionConfig,
+    servers: Vec<Arc<ServerState>>,
+    // Serializes ejection decisions so concurrent failures can't exceed the cap
+    ejecting: Mutex<()>,
+}
+
+impl OutlierDetector {
+    pub fn new(backend: String, config: OutlierDetectionConfig, servers: Vec<Arc<ServerState>>) -> Self {
+        OutlierDetector {
+            backend,
+            config,
+            servers,
+            ejecting: Mutex::new(()),
+        }
+    }
+
+    pub fn record_success(&self, server: &ServerState) {
+        server.outlier().consecutive_failures = 0;
+    }
+
+    /// Count a 5xx response or connect failure, ejecting the server once it hits the threshold
+    pub fn record_failure(&self, server: &ServerState) {
+        let now = Instant::now();
+
+        let failures = {
+            let mut state = server.outlier();
+            // Requests that were already in flight when the server was ejected don't count again
+            if state.is_ejected(now) {
+                return;
+            }
+
+            state.consecutive_failures += 1;
+            state.consecutive_failures
+        };
+
+        if failures < self.config.consecutive_failures {
+            return;
+        }
+
+        let _ejecting = self.ejecting.lock().unwrap();
+
+        let ejected = self.servers.iter()
+            .filter(|server| server.outlier().is_ejected(now))
+            .count();
+        let max_ejected = self.servers.len() * self.config.max_ejection_percent as usize / 100;
+
+        if ejected >= max_ejected {
+            // Keep trying on every further failure but only warn once per streak
+            if failures == self.config.consecutive_failures {
+                warn!(
+                    "Server {} in backend {} is failing but {} of {} servers are already ejected",
+                    server.url, self.backend, ejected, self.servers.len()
+                );
+            }
+            return;
+        }
+
+        let mut state = server.outlier();
+        let max_ejection_time = Duration::from_secs(self.config.max_ejection_time);
+
+        // A server that has stayed in rotation for a full max ejection time starts over
+        if state.ejected_until.is_some_and(|until| now.saturating_duration_since(until) >= max_ejection_time) {
+            state.ejections = 0;
+        }
+
+        let backoff = Duration::from_secs(self.config.base_ejection_time)
+            .saturating_mul(2u32.saturating_pow(state.ejections))
+            .min(max_ejection_time);
+
+        warn!(
+            "Ejecting server {} in backend {} for {}s after {} consecutive failures",
+            server.url, self.backend, backoff.as_secs(), state.consecutive_failures
+        );
+
+        state.ejections += 1;
+        state.consecutive_failures = 0;
+        state.ejected_until = Some(now + backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackendServerConfig;
+
+    fn detector(servers: usize) -> OutlierDetector {
+        let config = OutlierDetectionConfig {
+            consecutive_failures: 2,
+            base_ejection_time: 10,
+            max_ejection_time: 60,
+            max_ejection_percent: 50,
+        };
+        let servers = (0..servers)
+            .map(|i| {
+                let url = format!("http://server{}", i);
+                Arc::new(ServerState::new(&BackendServerConfig { url, weight: 1, backup: false }))
+            })
+            .collect();
+
+        OutlierDetector::new("api".to_string(), config, servers)
+    }
+
+    fn fail(detector: &OutlierDetector, server: usize, times: u32) {
+        for _ in 0..times {
+            detector.record_failure(&detector.servers[server]);
+        }
+    }
+
+    /// Seconds left on the server's ejection
+    fn ejected_for(server: &ServerState) -> u64 {
+        let until = server.outlier().ejected_until.unwrap();
+        until.saturating_duration_since(Instant::now()).as_secs_f64().round() as u64
+    }
+
+    // End the server's ejection `ago` in the past
+    fn expire(server: &ServerState, ago: Duration) {
+        server.outlier().ejected_until = Some(Instant::now().checked_sub(ago).unwrap());
+    }
+
+    #[test]
+    fn ejections_are_capped_at_max_ejection_percent() {
+        let detector = detector(4);
+
+        fail(&detector, 0, 2);
+        fail(&detector, 1, 2);
+        assert!(detector.servers[0].is_ejected());
+        assert!(detector.servers[1].is_ejected());
+
+        // Half of the servers are out already
+        fail(&detector, 2, 3);
+        assert!(!detector.servers[2].is_ejected());
+
+        // Once one comes back, the next failure ejects the other
+        expire(&detector.servers[0], Duration::ZERO);
+        fail(&detector, 2, 1);
+        assert!(detector.servers[2].is_ejected());
+    }
+
+    #[test]
+    fn ejection_time_doubles_up_to_the_maximum() {
+        let detector = detector(2);
+        let server = &detector.servers[0];
+
+        for expected in [10, 20, 40, 60, 60] {
+            fail(&detector, 0, 2);
+            assert_eq!(ejected_for(server), expected);
+            expire(server, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn ejections_start_over_after_a_clean_max_ejection_time() {
+        let detector = detector(2);
+        let server = &detector.servers[0];
+
+        fail(&detector, 0, 2);
+        expire(server, Duration::ZERO);
+        fail(&detector, 0, 2);
+        assert_eq!(ejected_for(server), 20);
+
+        expire(server, Duration::from_secs(59));
+        fail(&detector, 0, 2);
+        assert_eq!(ejected_for(server), 40);
+
+        expire(server, Duration::from_secs(60));
+        fail(&detector, 0, 2);
+        assert_eq!(ejected_for(server), 10);
+    }
+}
@@ -1,81 +1,839 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
-use tracing::{info, warn};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::header::{HeaderName, AUTHORIZATION};
+use hyper::{HeaderMap, Request};
+use tracing::warn;
+
+use crate::config::{self, RateLimitAlgorithm, RateLimitKeyConfig, RateLimitKeySource};
+use crate::features::load_balancer::hash_bytes;
+use crate::features::metrics::MetricsCollector;
+
+// Independently locked parts of the client map, so requests from different
+// clients rarely wait on each other
+const SHARDS: usize = 16;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+// Stands in for key parts a request doesn't have, so those requests share one allowance
+const MISSING_KEY_PART: &str = "-";
 
 #[derive(Debug, Clone)]
 pub struct RateLimitConfig {
-    pub requests_per_second: u32,
+    pub algorithm: RateLimitAlgorithm,
+    pub limit: u32,
     pub burst_size: u32,
-    pub window_seconds: u64,
+    pub window: Duration,
+    pub key: Vec<RateLimitKeyConfig>,
+    pub allow_list: Vec<Cidr>,
+    pub max_keys: usize,
+    pub headers: bool,
+}
+
+impl From<&config::RateLimitConfig> for RateLimitConfig {
+    fn from(config: &config::RateLimitConfig) -> Self {
+        RateLimitConfig {
+            algorithm: config.algorithm,
+            limit: config.limit,
+            burst_size: config.burst,
+            window: Duration::from_secs(config.window),
+            key: config.key.clone(),
+            // Entries were checked when the config was validated
+            allow_list: config.allow_list.iter()
+                .filter_map(|cidr| parse_cidr(cidr).ok())
+                .collect(),
+            max_keys: config.max_keys,
+            headers: config.headers,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    // Steady interval between requests for token-bucket and GCRA
+    fn emission_interval(&self) -> Duration {
+        self.window / self.limit
+    }
+
+    // Requests a client may send at once, the limit plus the burst allowance
+    fn capacity(&self) -> u32 {
+        self.limit.saturating_add(self.burst_size)
+    }
+
+    // Most requests a client can have left, the sliding window has no burst
+    fn quota(&self) -> u32 {
+        match self.algorithm {
+            RateLimitAlgorithm::SlidingWindow => self.limit,
+            RateLimitAlgorithm::TokenBucket | RateLimitAlgorithm::Gcra => self.capacity(),
+        }
+    }
 }
 
+/// Per-client state, constant size whatever the limit
 #[derive(Debug)]
-struct RequestWindow {
-    timestamps: Vec<Instant>,
-    total_requests: u64,
-    blocked_requests: u64,
+enum Allowance {
+    // Counts for the current and previous fixed window, weighted into an
+    // estimate of the sliding window
+    SlidingWindow {
+        window_start: Instant,
+        current: u32,
+        previous: u32,
+    },
+    TokenBucket {
+        tokens: f64,
+        updated: Instant,
+    },
+    // Generic cell rate algorithm: only the theoretical arrival time of the next request
+    Gcra {
+        theoretical_arrival: Instant,
+    },
+}
+
+impl Allowance {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        match config.algorithm {
+            RateLimitAlgorithm::SlidingWindow => Allowance::SlidingWindow {
+                window_start: now,
+                current: 0,
+                previous: 0,
+            },
+            RateLimitAlgorithm::TokenBucket => Allowance::TokenBucket {
+                tokens: config.capacity() as f64,
+                updated: now,
+            },
+            RateLimitAlgorithm::Gcra => Allowance::Gcra {
+                theoretical_arrival: now,
+            },
+        }
+    }
+
+    /// Whether the client has its full allowance back, so forgetting it changes nothing
+    fn is_idle(&self, config: &RateLimitConfig, now: Instant) -> bool {
+        match self {
+            Allowance::SlidingWindow { window_start, .. } => {
+                now.saturating_duration_since(*window_start) >= config.window * 2
+            }
+            Allowance::TokenBucket { tokens, updated } => {
+                let refilled = now.saturating_duration_since(*updated).as_secs_f64()
+                    / config.emission_interval().as_secs_f64();
+                tokens + refilled >= config.capacity() as f64
+            }
+            Allowance::Gcra { theoretical_arrival } => *theoretical_arrival <= now,
+        }
+    }
+
+    /// Time until the client has its full allowance back, as of its last request
+    fn reset(&self, config: &RateLimitConfig, now: Instant) -> Duration {
+        match self {
+            Allowance::SlidingWindow { window_start, current, previous } => {
+                let left_in_window = (*window_start + config.window).saturating_duration_since(now);
+                if *current > 0 {
+                    left_in_window + config.window
+                } else if *previous > 0 {
+                    left_in_window
+                } else {
+                    Duration::ZERO
+                }
+            }
+            Allowance::TokenBucket { tokens, .. } => {
+                let missing = (config.capacity() as f64 - tokens).max(0.0);
+                config.emission_interval().mul_f64(missing)
+            }
+            Allowance::Gcra { theoretical_arrival } => theoretical_arrival.saturating_duration_since(now),
+        }
+    }
+
+    /// Take one request, returning the requests left or the wait until the next one is allowed
+    fn acquire(&mut self, config: &RateLimitConfig, now: Instant) -> Result<u32, Duration> {
+        match self {
+            Allowance::SlidingWindow { window_start, current, previous } => {
+                let window = config.window;
+                let mut elapsed = now.saturating_duration_since(*window_start);
+
+                if elapsed >= window * 2 {
+                    *previous = 0;
+                    *current = 0;
+                    *window_start = now;
+                    elapsed = Duration::ZERO;
+                } else if elapsed >= window {
+                    *previous = *current;
+                    *current = 0;
+                    *window_start += window;
+                    elapsed -= window;
+                }
+
+                let overlap = 1.0 - elapsed.as_secs_f64() / window.as_secs_f64();
+                let estimate = *previous as f64 * overlap + *current as f64;
+                let limit = config.limit as f64;
+
+                if estimate + 1.0 <= limit {
+                    *current += 1;
+                    return Ok((limit - estimate - 1.0) as u32);
+                }
+
+                // Wait for enough of the previous window to slide out, or for the
+                // current count to become the previous one and slide out in turn
+                let wait = if (*current as f64) + 1.0 <= limit && *previous > 0 {
+                    let free_at = 1.0 - (limit - 1.0 - *current as f64) / *previous as f64;
+                    (window.as_secs_f64() * free_at - elapsed.as_secs_f64()).max(0.0)
+                } else {
+                    let free_at = (1.0 - (limit - 1.0) / *current as f64).max(0.0);
+                    (window - elapsed).as_secs_f64() + window.as_secs_f64() * free_at
+                };
+
+                Err(Duration::from_secs_f64(wait))
+            }
+            Allowance::TokenBucket { tokens, updated } => {
+                let rate = 1.0 / config.emission_interval().as_secs_f64();
+                let idle = now.saturating_duration_since(*updated).as_secs_f64();
+
+                *tokens = (*tokens + idle * rate).min(config.capacity() as f64);
+                *updated = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    Ok(*tokens as u32)
+                } else {
+                    Err(Duration::from_secs_f64((1.0 - *tokens) / rate))
+                }
+            }
+            Allowance::Gcra { theoretical_arrival } => {
+                let interval = config.emission_interval();
+                // How far ahead of the steady rate a client may run
+                let tolerance = interval * (config.capacity() - 1);
+                let arrival = (*theoretical_arrival).max(now);
+
+                let allowed_at = arrival.checked_sub(tolerance).unwrap_or(now);
+                if now < allowed_at {
+                    return Err(allowed_at - now);
+                }
+
+                *theoretical_arrival = arrival + interval;
+                let ahead = *theoretical_arrival - now;
+                Ok(((tolerance + interval).saturating_sub(ahead).as_secs_f64() / interval.as_secs_f64()) as u32)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientState {
+    allowance: Allowance,
+    remaining: u32,
+    // Position in the shard's recency order
+    stamp: u64,
+}
+
+#[derive(Default)]
+struct Shard {
+    clients: HashMap<String, ClientState>,
+    // Keys by the stamp of their last request, least recently seen first
+    recency: BTreeMap<u64, String>,
+    next_stamp: u64,
+}
+
+impl Shard {
+    // Move `key` to the most recently seen end
+    fn touch(&mut self, key: &str) -> Option<&mut ClientState> {
+        let stamp = self.next_stamp;
+        let client = self.clients.get_mut(key)?;
+        self.next_stamp += 1;
+
+        if let Some(key) = self.recency.remove(&client.stamp) {
+            self.recency.insert(stamp, key);
+        }
+        client.stamp = stamp;
+        Some(client)
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        match self.recency.pop_first() {
+            Some((_, key)) => self.clients.remove(&key).is_some(),
+            None => false,
+        }
+    }
 }
 
 pub struct RateLimiter {
+    // Route path or "global", for logs and metrics
+    policy: String,
     config: RateLimitConfig,
-    windows: Arc<RwLock<HashMap<String, RequestWindow>>>,
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+    // Keys tracked by this limiter, added into the shared metrics gauge
+    tracked_keys: Arc<AtomicU64>,
+    sweeping: AtomicBool,
 }
 
 impl RateLimiter {
-    pub fn new(config: RateLimitConfig) -> Self {
-        RateLimiter {
+    pub fn new(policy: &str, config: RateLimitConfig, metrics: &MetricsCollector) -> Arc<Self> {
+        Arc::new(RateLimiter {
+            policy: policy.to_string(),
             config,
-            windows: Arc::new(RwLock::new(HashMap::new())),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            tracked_keys: metrics.gauge(&tracked_keys_gauge(policy)),
+            sweeping: AtomicBool::new(false),
+        })
+    }
+
+    /// Start forgetting idle clients in the background until the limiter is dropped.
+    /// Limiters shared between listeners are only swept once
+    pub fn start_sweeper(self: &Arc<Self>) {
+        if self.sweeping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let weak = Arc::downgrade(self);
+        let period = self.config.window;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                match weak.upgrade() {
+                    Some(limiter) => limiter.sweep(),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    /// Key identifying the client of `req`, None if its peer is on the allow-list
+    pub fn client_key<B>(&self, req: &Request<B>, peer: Option<SocketAddr>) -> Option<String> {
+        let peer_ip = peer.map(|peer| peer.ip().to_canonical());
+        if peer_ip.is_some_and(|ip| self.config.allow_list.iter().any(|cidr| cidr.contains(ip))) {
+            return None;
         }
+
+        // Length-prefixed so a value containing the separator can't pose as several parts
+        let parts: Vec<String> = self.config.key.iter()
+            .map(|part| match key_part(part, req, peer_ip) {
+                Some(value) => format!("{}:{}", value.len(), value),
+                None => MISSING_KEY_PART.to_string(),
+            })
+            .collect();
+
+        Some(parts.join("|"))
+    }
+
+    /// Whether responses should carry the RateLimit headers
+    pub fn sends_headers(&self) -> bool {
+        self.config.headers
     }
 
-    pub async fn check_rate_limit(&self, ip: &str) -> bool {
+    /// Count a request for the client `key` and decide whether it may go through
+    pub fn check_rate_limit(&self, key: &str) -> RateLimitDecision {
         let now = Instant::now();
-        let window_duration = Duration::from_secs(self.config.window_seconds);
-        let mut windows = self.windows.write().await;
-        
-        let window = windows.entry(ip.to_string()).or_insert(RequestWindow {
-            timestamps: Vec::new(),
-            total_requests: 0,
-            blocked_requests: 0,
-        });
+        let mut shard = self.shard(key).lock().unwrap();
 
-        // Remove old timestamps
-        window.timestamps.retain(|&ts| now.duration_since(ts) < window_duration);
+        if shard.touch(key).is_none() {
+            // Evicting forgives that client's usage, so take the one seen longest ago
+            if shard.clients.len() >= self.max_keys_per_shard() && shard.evict_oldest() {
+                self.tracked_keys.fetch_sub(1, Ordering::Relaxed);
+            }
 
-        // Check if we're within limits
-        if window.timestamps.len() as u32 >= self.config.requests_per_second {
-            window.blocked_requests += 1;
-            warn!("Rate limit exceeded for IP: {}", ip);
-            false
-        } else {
-            window.timestamps.push(now);
-            window.total_requests += 1;
-            true
+            let stamp = shard.next_stamp;
+            shard.next_stamp += 1;
+            shard.recency.insert(stamp, key.to_string());
+            shard.clients.insert(key.to_string(), ClientState {
+                allowance: Allowance::new(&self.config, now),
+                remaining: self.config.limit,
+                stamp,
+            });
+            self.tracked_keys.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let client = shard.clients.get_mut(key).unwrap();
+
+        let (allowed, retry_after) = match client.allowance.acquire(&self.config, now) {
+            Ok(remaining) => {
+                client.remaining = remaining;
+                (true, Duration::ZERO)
+            }
+            Err(retry_after) => {
+                client.remaining = 0;
+                // Keys can hold API keys or tokens, so only log a fingerprint
+                warn!("Rate limit {} exceeded for client {:016x}", self.policy, hash_bytes(key.as_bytes()));
+                (false, retry_after)
+            }
+        };
+
+        RateLimitDecision {
+            allowed,
+            limit: self.config.quota(),
+            remaining: client.remaining,
+            reset: client.allowance.reset(&self.config, now),
+            retry_after,
         }
     }
 
-    pub async fn get_analytics(&self) -> HashMap<String, RateLimitAnalytics> {
-        let windows = self.windows.read().await;
-        let mut analytics = HashMap::new();
+    // Clients that are back to a full allowance carry no state worth keeping
+    fn sweep(&self) {
+        let now = Instant::now();
 
-        for (ip, window) in windows.iter() {
-            analytics.insert(ip.clone(), RateLimitAnalytics {
-                total_requests: window.total_requests,
-                blocked_requests: window.blocked_requests,
-                current_window_requests: window.timestamps.len() as u64,
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let Shard { clients, recency, .. } = &mut *shard;
+            let before = clients.len();
+
+            clients.retain(|_, client| {
+                let idle = client.allowance.is_idle(&self.config, now);
+                if idle {
+                    recency.remove(&client.stamp);
+                }
+                !idle
             });
+
+            self.tracked_keys.fetch_sub((before - clients.len()) as u64, Ordering::Relaxed);
         }
+    }
 
-        analytics
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    fn max_keys_per_shard(&self) -> usize {
+        self.config.max_keys.div_ceil(SHARDS)
     }
 }
 
-#[derive(Debug)]
-pub struct RateLimitAnalytics {
-    pub total_requests: u64,
-    pub blocked_requests: u64,
-    pub current_window_requests: u64,
-} 
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        let tracked: usize = self.shards.iter()
+            .map(|shard| shard.lock().unwrap().clients.len())
+            .sum();
+        self.tracked_keys.fetch_sub(tracked as u64, Ordering::Relaxed);
+    }
+}
+
+/// Outcome of counting one request against a policy
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    /// Most requests the client can have available
+    pub limit: u32,
+    /// Requests the client has left after this one
+    pub remaining: u32,
+    /// Time until the client has its full allowance back
+    pub reset: Duration,
+    /// Time until the next request would be allowed, zero if this one was
+    pub retry_after: Duration,
+}
+
+impl RateLimitDecision {
+    /// Add the RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset headers
+    pub fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, self.limit.into());
+        headers.insert(RATELIMIT_REMAINING, self.remaining.into());
+        headers.insert(RATELIMIT_RESET, ceil_secs(self.reset).into());
+    }
+}
+
+/// Whole seconds, rounded up so clients never come back too early
+pub fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Name of the metrics gauge counting the keys tracked for a policy
+fn tracked_keys_gauge(policy: &str) -> String {
+    format!("rate_limit_tracked_keys{{policy=\"{}\"}}", policy)
+}
+
+fn key_part<B>(part: &RateLimitKeyConfig, req: &Request<B>, peer_ip: Option<IpAddr>) -> Option<String> {
+    let name = part.name.as_deref().unwrap_or_default();
+
+    match part.source {
+        RateLimitKeySource::PeerAddress => peer_ip.map(|ip| ip.to_string()),
+        // The first forwarded address, only trustworthy behind a proxy that overwrites it
+        RateLimitKeySource::ClientIp => req.headers()
+            .get("X-Forwarded-For")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split(',').next())
+            .or_else(|| req.headers().get("X-Real-IP").and_then(|h| h.to_str().ok()))
+            .map(|ip| ip.trim().to_string())
+            .filter(|ip| !ip.is_empty())
+            .or_else(|| peer_ip.map(|ip| ip.to_string())),
+        RateLimitKeySource::Header => req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from),
+        RateLimitKeySource::JwtClaim => jwt_claim(req, name),
+        RateLimitKeySource::Path => match &part.template {
+            Some(template) => match_path_template(template, req.uri().path()),
+            None => Some(req.uri().path().to_string()),
+        },
+    }
+}
+
+// Reads a claim from the bearer token's payload. The signature isn't checked,
+// the token only has to decode
+fn jwt_claim<B>(req: &Request<B>, claim: &str) -> Option<String> {
+    let token = req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))?;
+
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+
+    match claims.get(claim)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+// Fills the template's `{name}` segments from the path, so "/users/{id}/*" keys
+// "/users/42/photos" as "/users/42/*". `*` matches any one segment and a
+// trailing `*` also matches everything below it. None if the path doesn't fit
+fn match_path_template(template: &str, path: &str) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    let mut key = Vec::new();
+    let parts: Vec<&str> = template.trim_start_matches('/').split('/').collect();
+
+    for (i, part) in parts.iter().enumerate() {
+        let segment = segments.next()?;
+
+        if part.starts_with('{') && part.ends_with('}') {
+            key.push(segment);
+        } else if *part == "*" {
+            if i == parts.len() - 1 {
+                segments.by_ref().for_each(drop);
+            }
+            key.push("*");
+        } else if *part == segment {
+            key.push(segment);
+        } else {
+            return None;
+        }
+    }
+
+    if segments.next().is_some() {
+        return None;
+    }
+
+    Some(format!("/{}", key.join("/")))
+}
+
+/// An address range from an allow-list
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => match 32u32.checked_sub(self.prefix as u32) {
+                Some(host_bits) => {
+                    let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+                    u32::from(network) & mask == u32::from(ip) & mask
+                }
+                None => false,
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => match 128u32.checked_sub(self.prefix as u32) {
+                Some(host_bits) => {
+                    let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+                    u128::from(network) & mask == u128::from(ip) & mask
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Parse "10.0.0.0/8" or a bare address, which covers just that address
+pub fn parse_cidr(cidr: &str) -> Result<Cidr, String> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (cidr, None),
+    };
+
+    let network: IpAddr = address.trim().parse()
+        .map_err(|_| format!("'{}' is not an IP address", address))?;
+    let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| format!("prefix length must be 0 to {}", max_prefix))?,
+        None => max_prefix,
+    };
+
+    // Peers are matched in canonical form, so an IPv4-mapped range becomes the
+    // IPv4 range it covers
+    match network.to_canonical() {
+        IpAddr::V4(mapped) if network.is_ipv6() => {
+            let prefix = prefix.checked_sub(96)
+                .ok_or_else(|| "prefix length of an IPv4-mapped range must be 96 to 128".to_string())?;
+            Ok(Cidr { network: IpAddr::V4(mapped), prefix })
+        }
+        network => Ok(Cidr { network, prefix }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse::<IpAddr>().unwrap().to_canonical()
+    }
+
+    fn config(algorithm: RateLimitAlgorithm, limit: u32, burst_size: u32, window: Duration) -> RateLimitConfig {
+        RateLimitConfig {
+            algorithm,
+            limit,
+            burst_size,
+            window,
+            key: Vec::new(),
+            allow_list: Vec::new(),
+            max_keys: 100_000,
+            headers: false,
+        }
+    }
+
+    // A key other than `key` that lands in the same shard
+    fn same_shard_key(limiter: &RateLimiter, key: &str) -> String {
+        (0..)
+            .map(|i| format!("other-{}", i))
+            .find(|other| other != key && std::ptr::eq(limiter.shard(other), limiter.shard(key)))
+            .unwrap()
+    }
+
+    fn is_tracked(limiter: &RateLimiter, key: &str) -> bool {
+        limiter.shard(key).lock().unwrap().clients.contains_key(key)
+    }
+
+    #[test]
+    fn full_shard_forgets_the_least_recently_seen_client() {
+        let mut config = config(RateLimitAlgorithm::TokenBucket, 1, 0, Duration::from_secs(60));
+        config.max_keys = SHARDS * 2;
+        let metrics = MetricsCollector::new();
+        let tracked = metrics.gauge(&tracked_keys_gauge("test"));
+        let limiter = RateLimiter::new("test", config, &metrics);
+
+        let first = same_shard_key(&limiter, "a");
+        let second = same_shard_key(&limiter, &first);
+        assert!(limiter.check_rate_limit("a").allowed);
+        assert!(limiter.check_rate_limit(&first).allowed);
+
+        // Seeing "a" again makes `first` the oldest
+        assert!(!limiter.check_rate_limit("a").allowed);
+        assert!(limiter.check_rate_limit(&second).allowed);
+
+        assert!(is_tracked(&limiter, "a"));
+        assert!(!is_tracked(&limiter, &first));
+        assert!(is_tracked(&limiter, &second));
+        assert_eq!(tracked.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn sweep_forgets_only_idle_clients() {
+        let config = config(RateLimitAlgorithm::Gcra, 1, 0, Duration::from_millis(50));
+        let metrics = MetricsCollector::new();
+        let tracked = metrics.gauge(&tracked_keys_gauge("test"));
+        let limiter = RateLimiter::new("test", config, &metrics);
+
+        limiter.check_rate_limit("idle");
+        std::thread::sleep(Duration::from_millis(60));
+        limiter.check_rate_limit("busy");
+        limiter.sweep();
+
+        assert!(!is_tracked(&limiter, "idle"));
+        assert!(is_tracked(&limiter, "busy"));
+        assert_eq!(tracked.load(Ordering::Relaxed), 1);
+
+        drop(limiter);
+        assert_eq!(tracked.load(Ordering::Relaxed), 0);
+    }
+
+    fn assert_secs(duration: Duration, secs: f64) {
+        assert!((duration.as_secs_f64() - secs).abs() < 1e-6, "{:?} is not {}s", duration, secs);
+    }
+
+    fn acquire_all(allowance: &mut Allowance, config: &RateLimitConfig, now: Instant, count: usize) -> Vec<u32> {
+        (0..count).map(|_| allowance.acquire(config, now).unwrap()).collect()
+    }
+
+    #[test]
+    fn token_bucket_allows_limit_plus_burst_then_refills() {
+        let config = config(RateLimitAlgorithm::TokenBucket, 2, 1, Duration::from_secs(2));
+        let start = Instant::now();
+        let mut allowance = Allowance::new(&config, start);
+
+        assert_eq!(acquire_all(&mut allowance, &config, start, 3), vec![2, 1, 0]);
+        assert_eq!(config.quota(), 3);
+        assert_secs(allowance.reset(&config, start), 3.0);
+        assert_secs(allowance.acquire(&config, start).unwrap_err(), 1.0);
+
+        let later = start + Duration::from_millis(500);
+        assert_secs(allowance.acquire(&config, later).unwrap_err(), 0.5);
+
+        let refilled = start + Duration::from_secs(1);
+        assert_eq!(allowance.acquire(&config, refilled), Ok(0));
+        assert!(!allowance.is_idle(&config, refilled));
+        assert!(allowance.is_idle(&config, refilled + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn gcra_allows_limit_plus_burst_then_spaces_requests() {
+        let config = config(RateLimitAlgorithm::Gcra, 2, 1, Duration::from_secs(2));
+        let start = Instant::now();
+        let mut allowance = Allowance::new(&config, start);
+
+        assert_eq!(acquire_all(&mut allowance, &config, start, 3), vec![2, 1, 0]);
+        assert_eq!(config.quota(), 3);
+        assert_secs(allowance.reset(&config, start), 3.0);
+        assert_secs(allowance.acquire(&config, start).unwrap_err(), 1.0);
+
+        let later = start + Duration::from_millis(500);
+        assert_secs(allowance.acquire(&config, later).unwrap_err(), 0.5);
+
+        let spaced = start + Duration::from_secs(1);
+        assert_eq!(allowance.acquire(&config, spaced), Ok(0));
+        assert!(!allowance.is_idle(&config, spaced));
+        assert!(allowance.is_idle(&config, start + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn sliding_window_weights_the_previous_window() {
+        let config = config(RateLimitAlgorithm::SlidingWindow, 4, 10, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut allowance = Allowance::new(&config, start);
+
+        // Burst doesn't apply to the sliding window
+        assert_eq!(acquire_all(&mut allowance, &config, start, 4), vec![3, 2, 1, 0]);
+        assert_eq!(config.quota(), 4);
+        assert_secs(allowance.reset(&config, start), 20.0);
+        assert_secs(allowance.acquire(&config, start).unwrap_err(), 12.5);
+
+        // All four requests still count in full at the start of the next window
+        let next_window = start + Duration::from_secs(10);
+        assert_secs(allowance.acquire(&config, next_window).unwrap_err(), 2.5);
+
+        // A quarter of the previous window has slid out, making room for one
+        let slid = start + Duration::from_millis(12_500);
+        assert_eq!(allowance.acquire(&config, slid), Ok(0));
+        assert!(!allowance.is_idle(&config, slid));
+        assert!(allowance.is_idle(&config, start + Duration::from_secs(30)));
+    }
+
+    fn key_config(source: RateLimitKeySource, name: Option<&str>) -> RateLimitKeyConfig {
+        RateLimitKeyConfig { source, name: name.map(String::from), template: None }
+    }
+
+    #[test]
+    fn key_parts_cant_collide_through_the_separator() {
+        let mut config = config(RateLimitAlgorithm::Gcra, 1, 0, Duration::from_secs(1));
+        config.key = vec![
+            key_config(RateLimitKeySource::Header, Some("a")),
+            key_config(RateLimitKeySource::Header, Some("b")),
+        ];
+        let limiter = RateLimiter::new("test", config, &MetricsCollector::new());
+
+        let key = |a: &str, b: &str| {
+            let req = Request::builder().header("a", a).header("b", b).body(()).unwrap();
+            limiter.client_key(&req, None).unwrap()
+        };
+
+        assert_ne!(key("x|1:y", "z"), key("x", "y|1:z"));
+        assert_eq!(key("x", "y"), "1:x|1:y");
+
+        let missing = Request::builder().header("a", "-").body(()).unwrap();
+        assert_eq!(limiter.client_key(&missing, None).unwrap(), "1:-|-");
+    }
+
+    #[test]
+    fn allow_listed_peers_have_no_key() {
+        let mut config = config(RateLimitAlgorithm::Gcra, 1, 0, Duration::from_secs(1));
+        config.key = vec![key_config(RateLimitKeySource::PeerAddress, None)];
+        config.allow_list = vec![parse_cidr("10.0.0.0/8").unwrap()];
+        let limiter = RateLimiter::new("test", config, &MetricsCollector::new());
+        let req = Request::new(());
+
+        assert_eq!(limiter.client_key(&req, Some("10.1.2.3:80".parse().unwrap())), None);
+        assert_eq!(limiter.client_key(&req, Some("[::ffff:10.1.2.3]:80".parse().unwrap())), None);
+        assert_eq!(
+            limiter.client_key(&req, Some("11.1.2.3:80".parse().unwrap())),
+            Some("8:11.1.2.3".to_string())
+        );
+    }
+
+    #[test]
+    fn path_template_keeps_named_segments() {
+        assert_eq!(match_path_template("/users/{id}", "/users/42").as_deref(), Some("/users/42"));
+        assert_eq!(match_path_template("/users/{id}/*", "/users/42/photos").as_deref(), Some("/users/42/*"));
+        assert_eq!(match_path_template("/users/{id}/*", "/users/42/posts/7").as_deref(), Some("/users/42/*"));
+        assert_eq!(match_path_template("/*/{id}/edit", "/posts/7/edit").as_deref(), Some("/*/7/edit"));
+    }
+
+    #[test]
+    fn path_template_rejects_paths_that_dont_fit() {
+        assert_eq!(match_path_template("/users/{id}", "/groups/42"), None);
+        assert_eq!(match_path_template("/users/{id}", "/users/42/photos"), None);
+        assert_eq!(match_path_template("/users/{id}/*", "/users/42"), None);
+        assert_eq!(match_path_template("/*/{id}/edit", "/posts/7/view"), None);
+    }
+
+    #[test]
+    fn cidr_matches_ipv4_ranges() {
+        let cidr = parse_cidr("10.1.0.0/16").unwrap();
+        assert!(cidr.contains(ip("10.1.200.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let any = parse_cidr("0.0.0.0/0").unwrap();
+        assert!(any.contains(ip("192.168.7.7")));
+    }
+
+    #[test]
+    fn cidr_without_prefix_is_one_address() {
+        let cidr = parse_cidr("192.168.1.5").unwrap();
+        assert!(cidr.contains(ip("192.168.1.5")));
+        assert!(!cidr.contains(ip("192.168.1.6")));
+    }
+
+    #[test]
+    fn cidr_matches_ipv6_ranges() {
+        let cidr = parse_cidr("2001:db8::/32").unwrap();
+        assert!(cidr.contains(ip("2001:db8:1::7")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+        assert!(!cidr.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_maps_ipv4_mapped_entries_to_ipv4() {
+        let single = parse_cidr("::ffff:10.0.0.1").unwrap();
+        assert!(single.contains(ip("10.0.0.1")));
+        assert!(single.contains(ip("::ffff:10.0.0.1")));
+        assert!(!single.contains(ip("192.168.7.7")));
+
+        let range = parse_cidr("::ffff:10.0.0.0/104").unwrap();
+        assert!(range.contains(ip("10.255.0.1")));
+        assert!(!range.contains(ip("11.0.0.1")));
+
+        assert!(parse_cidr("::ffff:10.0.0.0/64").is_err());
+    }
+
+    #[test]
+    fn cidr_rejects_bad_entries() {
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("2001:db8::/129").is_err());
+        assert!(parse_cidr("example.com").is_err());
+        assert!(parse_cidr("10.0.0.0/x").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Method, Response};
+use rand::Rng;
+
+use crate::config::{RetryCondition, RetryConfig};
+use crate::error::{ProxyError, ProxyResult};
+
+// Span the retry budget counts requests and retries over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Caps retries at a share of recent requests, so a struggling backend
+/// doesn't have its load multiplied by retry storms
+pub struct RetryBudget {
+    ratio: f64,
+    min_per_window: f64,
+    counts: Mutex<BudgetCounts>,
+}
+
+struct BudgetCounts {
+    window_start: Instant,
+    requests: u64,
+    retries: u64,
+    previous_requests: u64,
+    previous_retries: u64,
+}
+
+impl BudgetCounts {
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+
+        if elapsed >= BUDGET_WINDOW * 2 {
+            self.previous_requests = 0;
+            self.previous_retries = 0;
+            self.requests = 0;
+            self.retries = 0;
+            self.window_start = now;
+        } else if elapsed >= BUDGET_WINDOW {
+            self.previous_requests = self.requests;
+            self.previous_retries = self.retries;
+            self.requests = 0;
+            self.retries = 0;
+            self.window_start += BUDGET_WINDOW;
+        }
+    }
+
+    // Sliding estimate that weights the previous window by how much of it still
+    // overlaps the last BUDGET_WINDOW
+    fn estimate(&self, now: Instant, previous: u64, current: u64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.window_start).as_secs_f64();
+        let overlap = 1.0 - (elapsed / BUDGET_WINDOW.as_secs_f64()).min(1.0);
+        previous as f64 * overlap + current as f64
+    }
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64, min_retries_per_second: u32) -> Self {
+        RetryBudget {
+            ratio,
+            min_per_window: min_retries_per_second as f64 * BUDGET_WINDOW.as_secs_f64(),
+            counts: Mutex::new(BudgetCounts {
+                window_start: Instant::now(),
+                requests: 0,
+                retries: 0,
+                previous_requests: 0,
+                previous_retries: 0,
+            }),
+        }
+    }
+
+    pub fn record_request(&self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.roll(Instant::now());
+        counts.requests += 1;
+    }
+
+    /// Take a retry out of the budget, false if it is used up
+    pub fn try_retry(&self) -> bool {
+        let now = Instant::now();
+        let mut counts = self.counts.lock().unwrap();
+        counts.roll(now);
+
+        let requests = counts.estimate(now, counts.previous_requests, counts.requests);
+        let retries = counts.estimate(now, counts.previous_retries, counts.retries);
+
+        if retries + 1.0 > requests * self.ratio + self.min_per_window {
+            return false;
+        }
+
+        counts.retries += 1;
+        true
+    }
+}
+
+/// Methods that can safely be sent twice
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Exponential backoff with full jitter before retry number `retry` (starting at 1)
+pub fn backoff(config: &RetryConfig, retry: u32) -> Duration {
+    let ceiling = config.backoff_base_ms
+        .saturating_mul(1 << (retry.saturating_sub(1)).min(16))
+        .min(config.backoff_max_ms);
+
+    Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+}
+
+/// Whether the outcome of an attempt matches one of the retry conditions
+pub fn should_retry(config: &RetryConfig, result: &ProxyResult<Response<Body>>) -> bool {
+    match result {
+        Ok(response) => config.statuses.contains(&response.status().as_u16()),
+        Err(ProxyError::HttpError(e)) if e.is_connect() => config.retry_on.contains(&RetryCondition::ConnectFailure),
+        Err(ProxyError::TimeoutError(_)) => config.retry_on.contains(&RetryCondition::Timeout),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    fn budget(ratio: f64, min_retries_per_second: u32, requests: u32) -> RetryBudget {
+        let budget = RetryBudget::new(ratio, min_retries_per_second);
+        for _ in 0..requests {
+            budget.record_request();
+        }
+        budget
+    }
+
+    fn retries_allowed(budget: &RetryBudget) -> usize {
+        (0..1000).take_while(|_| budget.try_retry()).count()
+    }
+
+    // Move the budget's clock forward without sleeping
+    fn advance(budget: &RetryBudget, by: Duration) {
+        let mut counts = budget.counts.lock().unwrap();
+        counts.window_start = counts.window_start.checked_sub(by).unwrap();
+    }
+
+    #[test]
+    fn retries_are_a_share_of_requests() {
+        assert_eq!(retries_allowed(&budget(0.2, 0, 50)), 10);
+        assert_eq!(retries_allowed(&budget(0.2, 0, 0)), 0);
+    }
+
+    #[test]
+    fn some_retries_are_allowed_without_traffic() {
+        // 2 per second over the 10s window
+        assert_eq!(retries_allowed(&budget(0.2, 2, 0)), 20);
+        assert_eq!(retries_allowed(&budget(0.2, 2, 50)), 30);
+    }
+
+    #[test]
+    fn the_previous_window_still_counts() {
+        let budget = budget(0.5, 0, 10);
+        assert_eq!(retries_allowed(&budget), 5);
+
+        // Just rolled over, so the last window's retries are still spent
+        advance(&budget, BUDGET_WINDOW);
+        assert!(!budget.try_retry());
+
+        // Both windows are over and the budget starts from scratch
+        advance(&budget, BUDGET_WINDOW * 2);
+        assert!(!budget.try_retry());
+        for _ in 0..4 {
+            budget.record_request();
+        }
+        assert_eq!(retries_allowed(&budget), 2);
+    }
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            statuses: vec![502, 503],
+            retry_on: vec![RetryCondition::ConnectFailure],
+            backoff_base_ms: 100,
+            backoff_max_ms: 1000,
+            ..RetryConfig::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = config();
+
+        for (retry, ceiling) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let delays: Vec<_> = (0..200).map(|_| backoff(&config, retry)).collect();
+            assert!(delays.iter().all(|delay| *delay <= Duration::from_millis(ceiling)));
+            // Full jitter spreads the delays over the whole range
+            assert!(delays.iter().any(|delay| *delay > Duration::from_millis(ceiling / 2)));
+            assert!(delays.iter().any(|delay| *delay < Duration::from_millis(ceiling / 2)));
+        }
+    }
+
+    fn response(status: StatusCode) -> ProxyResult<Response<Body>> {
+        Ok(Response::builder().status(status).body(Body::empty()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn only_configured_outcomes_are_retried() {
+        let config = config();
+
+        assert!(should_retry(&config, &response(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(!should_retry(&config, &response(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert!(!should_retry(&config, &response(StatusCode::OK)));
+
+        // Timeouts aren't in retry_on here
+        assert!(!should_retry(&config, &Err(ProxyError::TimeoutError("slow".to_string()))));
+        assert!(!should_retry(&config, &Err(ProxyError::ConfigError("bad".to_string()))));
+
+        // Nothing listens on port 1
+        let uri = "http://127.0.0.1:1/".parse().unwrap();
+        let connect_error = hyper::Client::new().get(uri).await.unwrap_err();
+        assert!(should_retry(&config, &Err(ProxyError::HttpError(connect_error))));
+    }
+}
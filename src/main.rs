@@ -7,7 +7,9 @@ mod config;
 mod proxy;
 mod server;
 mod error;
+mod error_page;
 mod features;
+mod tls;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
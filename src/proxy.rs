@@ -1,116 +1,540 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
-use futures::future::BoxFuture;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Either};
+use futures::{Stream, StreamExt};
+use hyper::body::HttpBody;
 use hyper::client::{Client, HttpConnector};
-use hyper::{Body, Request, Response, StatusCode, Uri};
-use hyper::header::{HeaderMap, HeaderName, HeaderValue};
-use once_cell::sync::Lazy;
-use tokio::sync::RwLock;
-use tracing::{debug, error, info, warn};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, COOKIE};
+use hyper::{Body, Request, Response, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use regex::Regex;
+use tracing::{debug, error, warn};
 
-use crate::config::{BackendConfig, Config, RouteConfig};
+use crate::config::{BackendConfig, Config, HashKeyConfig, HashKeySource, ListenerConfig, RouteConfig};
 use crate::error::{ProxyError, ProxyResult};
+use crate::error_page::{ErrorPageSet, ErrorPages};
 use crate::features::Features;
+use crate::features::health_check::HealthChecker;
+use crate::features::load_balancer::{InFlightGuard, LoadBalancer, LoadBalancingStrategy, ServerState};
+use crate::features::outlier::OutlierDetector;
+use crate::features::ratelimit::{RateLimitDecision, RateLimiter};
+use crate::features::retry::{self, backoff, is_idempotent, RetryBudget};
+use crate::tls::{self, ClientCertInfo};
 
-// HTTP client with connection pooling
-static HTTP_CLIENT: Lazy<Client<HttpConnector>> = Lazy::new(|| {
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+// HTTP client with connection pooling, one per backend
+fn build_http_client(backend: &BackendConfig) -> ProxyResult<HttpClient> {
+    let tls = backend.tls.as_ref();
+    let uses_tls = tls.is_some() || backend.servers.iter().any(|server| server.url.starts_with("https://"));
+    
     let mut connector = HttpConnector::new();
     connector.set_nodelay(true);
     connector.set_keepalive(Some(Duration::from_secs(30)));
+    // Let https:// URIs through to the TLS layer
+    connector.enforce_http(false);
+    
+    // Plain HTTP backends never verify a certificate, so they skip loading the system roots
+    let tls_config = if uses_tls {
+        tls::build_client_config(tls)?
+    } else {
+        tls::build_plain_client_config()
+    };
+    
+    let builder = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http();
+    
+    let builder = match tls.and_then(|tls| tls.server_name.clone()) {
+        Some(server_name) => builder.with_server_name(server_name),
+        None => builder,
+    };
+    
+    let connector = builder.enable_http1().wrap_connector(connector);
     
-    Client::builder()
+    Ok(Client::builder()
         .pool_idle_timeout(Duration::from_secs(30))
-        .build(connector)
-});
+        .build(connector))
+}
+
+/// Per-connection details the listener attaches to every request
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_addr: SocketAddr,
+    pub client_cert: Option<Arc<ClientCertInfo>>,
+}
 
 pub struct ProxyService {
-    config: Config,
-    routes: Vec<RouteConfig>,
-    backends: HashMap<String, BackendState>,
-    features: Features,
+    routes: Arc<Vec<Route>>,
+    // Compiled once so listeners serving a group share its rate limits and retry budgets
+    route_groups: Arc<HashMap<String, Arc<Vec<Route>>>>,
+    // Set when the listener verifies client certificates
+    client_cert_headers: Option<ClientCertHeaders>,
+    // Identity headers of every listener, never taken from the client
+    untrusted_headers: Arc<Vec<HeaderName>>,
+    // Shared by every listener so balancing and breaker state is global
+    backends: Arc<HashMap<String, BackendState>>,
+    features: Arc<Features>,
+    error_pages: Arc<ErrorPages>,
+    // The listener's own pages, consulted after the route's
+    listener_error_pages: ErrorPageSet,
+    intercept_errors: bool,
+}
+
+struct Route {
+    config: RouteConfig,
+    client_cert_subject: Option<Regex>,
+    error_pages: ErrorPageSet,
+    retry_budget: Option<RetryBudget>,
+    // The route's own limiter, or the shared global one
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Route {
+    fn new(config: RouteConfig, features: &Features) -> ProxyResult<Self> {
+        let client_cert_subject = config.client_cert_subject.as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| ProxyError::ConfigError(format!("Invalid client_cert_subject for route '{}': {}", config.path, e)))?;
+        
+        let error_pages = ErrorPageSet::new(&config.error_pages)?;
+        // Hedge copies are charged to the same budget as retries
+        let retry_budget = (config.retry.is_some() || config.hedge.is_some()).then(|| {
+            let retry = config.retry.clone().unwrap_or_default();
+            RetryBudget::new(retry.budget_ratio, retry.min_retries_per_second)
+        });
+        
+        let rate_limiter = match &config.rate_limit {
+            Some(rate_limit) => Some(RateLimiter::new(&config.path, rate_limit.into(), &features.metrics_collector)),
+            None => features.rate_limiter.clone(),
+        };
+        
+        Ok(Route { config, client_cert_subject, error_pages, retry_budget, rate_limiter })
+    }
+}
+
+struct ClientCertHeaders {
+    subject: HeaderName,
+    san: HeaderName,
 }
 
 struct BackendState {
     config: BackendConfig,
-    next_server_index: RwLock<usize>,
+    balancer: LoadBalancer,
+    client: HttpClient,
+    outlier_detector: Option<OutlierDetector>,
 }
 
-pub fn create_proxy_service(config: Config) -> ProxyService {
-    let backends = config.backends
-        .iter()
-        .map(|(name, backend_config)| {
-            let backend_state = BackendState {
-                config: backend_config.clone(),
-                next_server_index: RwLock::new(0),
-            };
-            
-            (name.clone(), backend_state)
-        })
-        .collect();
+pub fn create_proxy_service(config: Config) -> ProxyResult<ProxyService> {
+    let mut backends = HashMap::new();
     
-    ProxyService {
-        routes: config.routes.clone(),
-        backends,
-        config,
-        features: Features::new(),
+    for (name, backend_config) in &config.backends {
+        let strategy = backend_config.load_balancing.parse::<LoadBalancingStrategy>()
+            .map_err(|e| ProxyError::ConfigError(format!("Backend '{}': {}", name, e)))?;
+        
+        let balancer = LoadBalancer::new(strategy, &backend_config.servers);
+        let outlier_detector = backend_config.outlier_detection.clone()
+            .map(|outlier| OutlierDetector::new(name.clone(), outlier, balancer.servers().to_vec()));
+        
+        let backend_state = BackendState {
+            config: backend_config.clone(),
+            balancer,
+            client: build_http_client(backend_config)?,
+            outlier_detector,
+        };
+        
+        backends.insert(name.clone(), backend_state);
     }
+    
+    let features = Arc::new(Features::new(&config));
+    
+    let untrusted_headers = config.client_cert_header_names().iter()
+        .map(|name| parse_header_name(name))
+        .collect::<ProxyResult<Vec<_>>>()?;
+    
+    let route_groups = config.route_groups.into_iter()
+        .map(|(name, routes)| Ok((name, Arc::new(compile_routes(routes, &features)?))))
+        .collect::<ProxyResult<HashMap<_, _>>>()?;
+    
+    // Listeners get their routes from `for_listener`, this service only holds the shared state
+    Ok(ProxyService {
+        routes: Arc::new(Vec::new()),
+        route_groups: Arc::new(route_groups),
+        client_cert_headers: None,
+        untrusted_headers: Arc::new(untrusted_headers),
+        backends: Arc::new(backends),
+        features,
+        error_pages: Arc::new(ErrorPages::new(&config.error_responses)?),
+        listener_error_pages: ErrorPageSet::default(),
+        intercept_errors: false,
+    })
+}
+
+fn compile_routes(routes: Vec<RouteConfig>, features: &Features) -> ProxyResult<Vec<Route>> {
+    routes.into_iter()
+        .map(|route| Route::new(route, features))
+        .collect()
 }
 
 impl ProxyService {
-    pub async fn proxy_request(&self, req: Request<Body>) -> ProxyResult<Response<Body>> {
-        let start_time = Instant::now();
-        let path = req.uri().path();
+    /// Create a service for a listener that shares backends and features
+    pub fn for_listener(&self, listener: &ListenerConfig) -> ProxyResult<ProxyService> {
+        let client_cert_headers = match &listener.tls {
+            Some(tls) if tls.client_ca_path.is_some() => Some(ClientCertHeaders {
+                subject: parse_header_name(&tls.client_cert_header)?,
+                san: parse_header_name(&tls.client_cert_san_header)?,
+            }),
+            _ => None,
+        };
+        
+        let routes = match &listener.route_group {
+            Some(group) => self.route_groups.get(group).cloned().unwrap_or_default(),
+            None => Arc::new(compile_routes(listener.routes.clone(), &self.features)?),
+        };
+        
+        Ok(ProxyService {
+            routes,
+            route_groups: self.route_groups.clone(),
+            client_cert_headers,
+            untrusted_headers: self.untrusted_headers.clone(),
+            backends: self.backends.clone(),
+            features: self.features.clone(),
+            error_pages: self.error_pages.clone(),
+            listener_error_pages: ErrorPageSet::new(&listener.error_pages)?,
+            intercept_errors: listener.intercept_errors,
+        })
+    }
+    
+    /// Start active health checking for every backend that configures it
+    pub fn start_health_checks(&self) -> ProxyResult<()> {
+        for (name, backend) in self.backends.iter() {
+            if let Some(health_check) = &backend.config.health_check {
+                HealthChecker::new(
+                    name.clone(),
+                    health_check.clone(),
+                    backend.balancer.servers().to_vec(),
+                    backend.client.clone(),
+                )?.spawn();
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Start sweeping idle clients out of the rate limiters this service uses
+    pub fn start_rate_limit_sweepers(&self) {
+        let route_limiters = self.routes.iter()
+            .chain(self.route_groups.values().flat_map(|routes| routes.iter()))
+            .filter_map(|route| route.rate_limiter.as_ref());
+        
+        for rate_limiter in self.features.rate_limiter.iter().chain(route_limiters) {
+            rate_limiter.start_sweeper();
+        }
+    }
+    
+    /// Log a metrics summary every `interval` seconds, never if it is 0
+    pub fn start_metrics_log(&self, interval: u64) {
+        if interval == 0 {
+            return;
+        }
+        
+        let metrics = self.features.metrics_collector.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval));
+            // The first tick fires right away, before anything was measured
+            interval.tick().await;
+            
+            loop {
+                interval.tick().await;
+                metrics.log_metrics().await;
+            }
+        });
+    }
+    
+    /// Proxy a request, turning failures into error responses for the client
+    pub async fn proxy_request(&self, req: Request<Body>) -> Response<Body> {
+        let route = self.find_route(req.uri().path());
+        let rate_limit = self.check_rate_limit(&req, route);
+        
+        let result = match rate_limit {
+            Some((_, decision)) if !decision.allowed => {
+                Err(ProxyError::RateLimitExceeded { retry_after: decision.retry_after })
+            }
+            _ => self.route_request(req, route).await,
+        };
+        
+        let mut error_pages = Vec::with_capacity(2);
+        if let Some(route) = route {
+            error_pages.push(&route.error_pages);
+        }
+        error_pages.push(&self.listener_error_pages);
+        
+        let mut response = match result {
+            Ok(response) => {
+                let intercept = route.and_then(|route| route.config.intercept_errors)
+                    .unwrap_or(self.intercept_errors);
+                
+                if intercept {
+                    self.error_pages.intercept(response, &error_pages)
+                } else {
+                    response
+                }
+            }
+            Err(e) => {
+                if e.status_code().is_server_error() {
+                    error!("Error handling request: {}", e);
+                } else {
+                    warn!("Rejected request: {}", e);
+                }
+                
+                self.error_pages.render(&e, &error_pages)
+            }
+        };
+        
+        if let Some((rate_limiter, decision)) = rate_limit {
+            if rate_limiter.sends_headers() {
+                decision.set_headers(response.headers_mut());
+            }
+        }
+        
+        response
+    }
+    
+    /// Count the request against its route's rate limit, requests that match no
+    /// route count against the global one. None if no limit applies
+    fn check_rate_limit<'a>(&'a self, req: &Request<Body>, route: Option<&'a Route>) -> Option<(&'a RateLimiter, RateLimitDecision)> {
+        let rate_limiter = match route {
+            Some(route) => route.rate_limiter.as_deref(),
+            None => self.features.rate_limiter.as_deref(),
+        }?;
+        
+        let peer_addr = req.extensions().get::<ConnectionInfo>().map(|conn| conn.peer_addr);
+        let key = rate_limiter.client_key(req, peer_addr)?;
+        
+        Some((rate_limiter, rate_limiter.check_rate_limit(&key)))
+    }
+    
+    async fn route_request(&self, mut req: Request<Body>, route: Option<&Route>) -> ProxyResult<Response<Body>> {
+        let client_cert = req.extensions()
+            .get::<ConnectionInfo>()
+            .and_then(|conn| conn.client_cert.clone());
+        let path = req.uri().path().to_string();
         let client_ip = get_client_ip(&req).unwrap_or("unknown".to_string());
         
         debug!("Received request for path: {} from {}", path, client_ip);
         
-        // Check rate limit
-        if !self.features.rate_limiter.check_rate_limit(&client_ip).await {
-            return Err(ProxyError::RateLimitExceeded);
+        let route = route.ok_or_else(|| ProxyError::RouteNotFound(path.clone()))?;
+        
+        // Check the client certificate if the route requires one
+        if let Some(pattern) = &route.client_cert_subject {
+            if !client_cert.as_ref().is_some_and(|cert| cert.matches(pattern)) {
+                return Err(ProxyError::Forbidden(format!("Client certificate not allowed for {}", route.config.path)));
+            }
         }
         
-        // Find matching route
-        let route = self.find_route(path)
-            .ok_or_else(|| ProxyError::RouteNotFound(path.to_string()))?;
+        // A backend behind several listeners must not see an identity forged on
+        // one without client certificates, so strip every listener's headers
+        for name in self.untrusted_headers.iter() {
+            req.headers_mut().remove(name);
+        }
+        
+        // Pass the verified identity upstream
+        if let Some(headers) = &self.client_cert_headers {
+            set_client_cert_headers(req.headers_mut(), headers, client_cert.as_deref());
+        }
         
         // Get backend for the route
-        let backend = self.backends.get(&route.backend)
-            .ok_or_else(|| ProxyError::BackendError(format!("Backend not found: {}", route.backend)))?;
+        let backend_name = &route.config.backend;
+        let backend = self.backends.get(backend_name)
+            .ok_or_else(|| ProxyError::BackendError(format!("Backend not found: {}", backend_name)))?;
         
         // Check circuit breaker
-        if !self.features.circuit_breaker.pre_request(&route.backend).await {
+        if !self.features.circuit_breaker.pre_request(backend_name).await {
             return Err(ProxyError::CircuitBreakerOpen);
         }
         
+        let (parts, body) = req.into_parts();
+        let head = Request::from_parts(parts, ());
+        
+        // Retries and hedged copies need a body that can be sent again, so small ones are buffered
+        let retry = route.config.retry.as_ref()
+            .filter(|retry| retry.non_idempotent || is_idempotent(head.method()));
+        let hedge = route.config.hedge.as_ref()
+            .filter(|_| is_idempotent(head.method()));
+        let mut body = if retry.is_some() || hedge.is_some() {
+            buffer_body(body, retry.map_or(0, |retry| retry.buffer_body_bytes)).await?
+        } else {
+            RequestBody::Streaming(body)
+        };
+        let replayable = matches!(body, RequestBody::Buffered(_));
+        let max_retries = match retry {
+            Some(retry) if replayable => retry.max_retries,
+            _ => 0,
+        };
+        let hedge = hedge.filter(|_| replayable);
+        
+        if let Some(budget) = &route.retry_budget {
+            budget.record_request();
+        }
+        
         // Select a backend server using load balancing
-        let target_server = self.select_backend_server(backend).await?;
+        let mut tried = Vec::new();
+        let mut server = self.select_backend_server(backend, &head, &tried)?;
+        let mut retries = 0;
+        
+        loop {
+            let result = match hedge {
+                Some(_) => self.hedged_attempt(&head, &mut body, route, backend, &server, &mut tried).await,
+                None => self.attempt(&head, body.take(), &route.config, backend, server.clone()).await,
+            };
+            
+            let retry = match retry {
+                Some(retry) if retries < max_retries && retry::should_retry(retry, &result) => retry,
+                _ => return result,
+            };
+            
+            // An open circuit turns away new requests, and a half-open one only
+            // admits its trials, so neither should get extra attempts
+            if !self.features.circuit_breaker.is_closed(backend_name).await {
+                debug!("Not retrying {}, the circuit for backend {} isn't closed", path, backend_name);
+                return result;
+            }
+            
+            if !route.retry_budget.as_ref().is_some_and(RetryBudget::try_retry) {
+                debug!("Retry budget for route {} is used up", route.config.path);
+                return result;
+            }
+            
+            // Try another server, unless every one of them has failed already.
+            // The balancer falls back to tried servers when none is left, so
+            // its pick is checked against them
+            tried.push(server);
+            server = match self.select_backend_server(backend, &head, &tried) {
+                Ok(server) if !tried.iter().any(|tried| Arc::ptr_eq(tried, &server)) => server,
+                _ => {
+                    debug!("No untried server left to retry {} on", path);
+                    return result;
+                }
+            };
+            
+            retries += 1;
+            debug!("Retrying {} on {} ({}/{})", path, server.url, retries, max_retries);
+            tokio::time::sleep(backoff(retry, retries)).await;
+        }
+    }
+    
+    /// Send the request to `server` and, if it hasn't answered within the hedge
+    /// delay, race a copy on another server. Whichever answers first wins and
+    /// the other request is cancelled
+    async fn hedged_attempt(
+        &self,
+        head: &Request<()>,
+        body: &mut RequestBody,
+        route: &Route,
+        backend: &BackendState,
+        server: &Arc<ServerState>,
+        tried: &mut Vec<Arc<ServerState>>,
+    ) -> ProxyResult<Response<Body>> {
+        let delay = Duration::from_millis(route.config.hedge.as_ref().map_or(0, |hedge| hedge.delay_ms));
+        let first = self.attempt(head, body.take(), &route.config, backend, server.clone());
+        tokio::pin!(first);
+        
+        if let Ok(result) = tokio::time::timeout(delay, &mut first).await {
+            return result;
+        }
+        
+        // Half-open trials and requests racing a circuit that just opened go out once
+        if !self.features.circuit_breaker.is_closed(&route.config.backend).await {
+            return first.await;
+        }
+        
+        // A copy on the same server wouldn't help
+        let exclude: Vec<_> = tried.iter().chain([server]).cloned().collect();
+        let hedge_server = match self.select_backend_server(backend, head, &exclude) {
+            Ok(hedge_server) if !Arc::ptr_eq(&hedge_server, server) => hedge_server,
+            _ => return first.await,
+        };
+        
+        // A slow backend shouldn't get twice the traffic
+        if !route.retry_budget.as_ref().is_some_and(RetryBudget::try_retry) {
+            debug!("Retry budget for route {} is used up, not hedging", route.config.path);
+            return first.await;
+        }
+        
+        debug!("Hedging request to {} after {:?}", hedge_server.url, delay);
+        tried.push(hedge_server.clone());
+        
+        let second = self.attempt(head, body.take(), &route.config, backend, hedge_server);
+        tokio::pin!(second);
+        
+        // Dropping the losing future cancels its request. A failure only
+        // wins if the other copy fails as well
+        match future::select(first, second).await {
+            Either::Left((Ok(response), _)) | Either::Right((Ok(response), _)) => Ok(response),
+            Either::Left((Err(_), other)) | Either::Right((Err(_), other)) => other.await,
+        }
+    }
+    
+    /// Send one attempt to `server` and record its outcome
+    async fn attempt(
+        &self,
+        head: &Request<()>,
+        body: Body,
+        route: &RouteConfig,
+        backend: &BackendState,
+        target_server: Arc<ServerState>,
+    ) -> ProxyResult<Response<Body>> {
+        let start_time = Instant::now();
+        let backend_name = &route.backend;
         
         // Build the target URI
-        let target_uri = self.build_target_uri(&req, route, target_server).await?;
+        let target_uri = self.build_target_uri(head.uri(), route, &target_server.url).await?;
+        
+        // Held until the response body finishes streaming
+        let in_flight = target_server.start_request();
         
         debug!("Forwarding request to: {}", target_uri);
         
         // Forward the request to the target server
-        match self.forward_request(req, target_uri).await {
+        match self.forward_request(head, body, target_uri, backend).await {
             Ok(response) => {
                 // Record success metrics
-                self.features.circuit_breaker.record_success(&route.backend).await;
+                target_server.record_latency(start_time.elapsed());
+                if let Some(detector) = &backend.outlier_detector {
+                    if response.status().is_server_error() {
+                        detector.record_failure(&target_server);
+                    } else {
+                        detector.record_success(&target_server);
+                    }
+                }
+                self.features.circuit_breaker.record_response(backend_name, response.status().as_u16()).await;
                 self.features.metrics_collector.record_request(
-                    &route.backend,
+                    backend_name,
                     start_time,
                     response.status().as_u16(),
                     false
                 ).await;
                 
-                Ok(response)
+                Ok(response.map(|body| track_body(body, in_flight)))
             }
             Err(e) => {
-                // Record failure metrics
-                self.features.circuit_breaker.record_failure(&route.backend).await;
+                // Record failure metrics, counting a failure as a full timeout so
+                // a server that fails fast doesn't look like the fastest one
+                target_server.record_latency(Duration::from_secs(backend.config.timeout));
+                if let (Some(detector), ProxyError::HttpError(e)) = (&backend.outlier_detector, &e) {
+                    if e.is_connect() {
+                        detector.record_failure(&target_server);
+                    }
+                }
+                self.features.circuit_breaker.record_failure(backend_name).await;
                 self.features.metrics_collector.record_request(
-                    &route.backend,
+                    backend_name,
                     start_time,
                     500,
                     true
@@ -121,29 +545,24 @@ impl ProxyService {
         }
     }
     
-    fn find_route(&self, path: &str) -> Option<&RouteConfig> {
+    fn find_route(&self, path: &str) -> Option<&Route> {
         self.routes.iter()
-            .find(|route| path.starts_with(&route.path))
+            .find(|route| path.starts_with(&route.config.path))
     }
     
-    async fn select_backend_server(&self, backend: &BackendState) -> ProxyResult<String> {
-        let servers = &backend.config.servers;
-        if servers.is_empty() {
-            return Err(ProxyError::NoHealthyBackends);
-        }
-        
-        let mut index = backend.next_server_index.write().await;
-        let server = servers[*index].clone();
-        
-        // Update the index for the next request
-        *index = (*index + 1) % servers.len();
+    fn select_backend_server(&self, backend: &BackendState, req: &Request<()>, exclude: &[Arc<ServerState>]) -> ProxyResult<Arc<ServerState>> {
+        let hash_key = match backend.balancer.strategy() {
+            LoadBalancingStrategy::ConsistentHash => request_hash_key(req, backend.config.hash_key.as_ref()),
+            _ => None,
+        };
         
-        Ok(server)
+        backend.balancer.select(hash_key.as_deref().map(str::as_bytes), exclude)
+            .ok_or(ProxyError::NoHealthyBackends)
     }
     
-    async fn build_target_uri(&self, req: &Request<Body>, route: &RouteConfig, server: String) -> ProxyResult<Uri> {
-        let path = req.uri().path();
-        let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+    async fn build_target_uri(&self, uri: &Uri, route: &RouteConfig, server: &str) -> ProxyResult<Uri> {
+        let path = uri.path();
+        let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
         
         let target_path = if route.strip_prefix {
             path.strip_prefix(&route.path)
@@ -165,30 +584,28 @@ impl ProxyService {
             .map_err(|e| ProxyError::BackendError(format!("Invalid URI: {}", e)))
     }
     
-    async fn forward_request(&self, mut req: Request<Body>, target_uri: Uri) -> ProxyResult<Response<Body>> {
-        let (parts, body) = req.into_parts();
-        
+    async fn forward_request(&self, head: &Request<()>, body: Body, target_uri: Uri, backend: &BackendState) -> ProxyResult<Response<Body>> {
         let mut outgoing_req = Request::builder()
-            .method(parts.method)
+            .method(head.method().clone())
             .uri(target_uri)
-            .version(parts.version);
+            .version(head.version());
         
         let headers = outgoing_req.headers_mut().unwrap();
-        copy_headers(&parts.headers, headers);
+        copy_headers(head.headers(), headers);
         
-        if let Some(host) = parts.uri.host() {
+        if let Some(host) = head.uri().host() {
             if let Ok(value) = HeaderValue::from_str(host) {
                 headers.insert("X-Forwarded-Host", value);
             }
         }
         
-        if let Some(port) = parts.uri.port_u16() {
+        if let Some(port) = head.uri().port_u16() {
             if let Ok(value) = HeaderValue::from_str(&port.to_string()) {
                 headers.insert("X-Forwarded-Port", value);
             }
         }
         
-        if let Some(scheme) = parts.uri.scheme_str() {
+        if let Some(scheme) = head.uri().scheme_str() {
             if let Ok(value) = HeaderValue::from_str(scheme) {
                 headers.insert("X-Forwarded-Proto", value);
             }
@@ -197,11 +614,11 @@ impl ProxyService {
         let outgoing_req = outgoing_req.body(body)
             .map_err(|e| ProxyError::BackendError(format!("Failed to build request: {}", e)))?;
         
-        let timeout_duration = Duration::from_secs(backend_timeout(&self.backends, self.find_route(outgoing_req.uri().path())));
+        let timeout_duration = Duration::from_secs(backend.config.timeout);
         
         let response = tokio::time::timeout(
             timeout_duration, 
-            HTTP_CLIENT.request(outgoing_req)
+            backend.client.request(outgoing_req)
         ).await
             .map_err(|_| ProxyError::TimeoutError(format!("Request timed out after {} seconds", timeout_duration.as_secs())))?
             .map_err(ProxyError::HttpError)?;
@@ -210,14 +627,97 @@ impl ProxyService {
     }
 }
 
-fn backend_timeout(backends: &HashMap<String, BackendState>, route: Option<&RouteConfig>) -> u64 {
-    if let Some(route) = route {
-        if let Some(backend) = backends.get(&route.backend) {
-            return backend.config.timeout;
+/// Request body that is either kept in memory for retries or streamed once
+enum RequestBody {
+    Buffered(Bytes),
+    Streaming(Body),
+}
+
+impl RequestBody {
+    /// Body for the next attempt, a streamed body can only be taken once
+    fn take(&mut self) -> Body {
+        match self {
+            RequestBody::Buffered(bytes) => Body::from(bytes.clone()),
+            RequestBody::Streaming(body) => std::mem::take(body),
+        }
+    }
+}
+
+/// Read the body into memory if it fits in `limit` bytes, otherwise hand back
+/// a stream that replays what was read followed by the rest
+async fn buffer_body(mut body: Body, limit: usize) -> ProxyResult<RequestBody> {
+    if body.is_end_stream() {
+        return Ok(RequestBody::Buffered(Bytes::new()));
+    }
+    
+    // Don't bother reading bodies that announce they are too large
+    if HttpBody::size_hint(&body).lower() > limit as u64 {
+        return Ok(RequestBody::Streaming(body));
+    }
+    
+    let mut buffered = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buffered.extend_from_slice(&chunk.map_err(ProxyError::RequestBodyError)?);
+        
+        if buffered.len() > limit {
+            let prefix = futures::stream::once(async move { Ok::<_, hyper::Error>(buffered.freeze()) });
+            return Ok(RequestBody::Streaming(Body::wrap_stream(prefix.chain(body))));
+        }
+    }
+    
+    Ok(RequestBody::Buffered(buffered.freeze()))
+}
+
+/// Keep the in-flight guard alive until the body has been fully streamed
+fn track_body(body: Body, in_flight: InFlightGuard) -> Body {
+    Body::wrap_stream(TrackedBody {
+        body,
+        in_flight: Some(in_flight),
+    })
+}
+
+struct TrackedBody {
+    body: Body,
+    in_flight: Option<InFlightGuard>,
+}
+
+impl Stream for TrackedBody {
+    type Item = Result<Bytes, hyper::Error>;
+    
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        
+        // Release as soon as the stream ends instead of waiting for the drop
+        if let Poll::Ready(None) | Poll::Ready(Some(Err(_))) = poll {
+            self.in_flight.take();
+        }
+        
+        poll
+    }
+}
+
+fn set_client_cert_headers(headers: &mut HeaderMap, names: &ClientCertHeaders, client_cert: Option<&ClientCertInfo>) {
+    let Some(client_cert) = client_cert else {
+        return;
+    };
+    
+    match HeaderValue::from_str(&client_cert.subject) {
+        Ok(value) => {
+            headers.insert(names.subject.clone(), value);
         }
+        Err(_) => warn!("Client certificate subject can't be sent as a header: {}", client_cert.subject),
     }
     
-    30
+    if !client_cert.sans.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&client_cert.sans.join(", ")) {
+            headers.insert(names.san.clone(), value);
+        }
+    }
+}
+
+fn parse_header_name(name: &str) -> ProxyResult<HeaderName> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| ProxyError::ConfigError(format!("Invalid header name '{}'", name)))
 }
 
 fn copy_headers(src: &HeaderMap, dst: &mut HeaderMap) {
@@ -232,7 +732,35 @@ fn copy_headers(src: &HeaderMap, dst: &mut HeaderMap) {
     }
 }
 
-fn get_client_ip(req: &Request<Body>) -> Option<String> {
+/// Extract the consistent-hash key, defaulting to the client IP
+fn request_hash_key<B>(req: &Request<B>, config: Option<&HashKeyConfig>) -> Option<String> {
+    let source = config.map(|c| c.source).unwrap_or(HashKeySource::ClientIp);
+    let name = config.and_then(|c| c.name.as_deref()).unwrap_or_default();
+    
+    match source {
+        HashKeySource::ClientIp => get_client_ip(req)
+            .or_else(|| req.extensions().get::<ConnectionInfo>().map(|conn| conn.peer_addr.ip().to_string())),
+        HashKeySource::Header => req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from),
+        HashKeySource::Cookie => get_cookie(req, name),
+        HashKeySource::Path => Some(req.uri().path().to_string()),
+    }
+}
+
+fn get_cookie<B>(req: &Request<B>, name: &str) -> Option<String> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn get_client_ip<B>(req: &Request<B>) -> Option<String> {
     req.headers()
         .get("X-Forwarded-For")
         .and_then(|h| h.to_str().ok())
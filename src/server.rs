@@ -1,67 +1,249 @@
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use hyper::server::conn::{AddrStream, Http};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::header::{HOST, LOCATION};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tracing::{error, info};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
 
-use crate::config::Config;
-use crate::proxy::{ProxyService, create_proxy_service};
+use crate::config::{Config, HttpsRedirectConfig};
+use crate::proxy::{ConnectionInfo, ProxyService, create_proxy_service};
+use crate::tls::{self, ClientCertInfo};
+
+// Clients that don't finish the TLS handshake by then are dropped, so idle
+// sockets can't pile up
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn run(config: Config) -> Result<()> {
-    let addr = config.server.listen_addr;
-    
     // Create shared proxy service
-    let proxy_service = create_proxy_service(config.clone());
-    let proxy_service = Arc::new(RwLock::new(proxy_service));
+    let proxy_service = create_proxy_service(config.clone())?;
+    proxy_service.start_health_checks()?;
+    proxy_service.start_metrics_log(config.metrics_log_interval);
+    
+    let mut listeners = JoinSet::new();
+    
+    for listener in config.listeners() {
+        let name = listener.display_name();
+        let mode = match &listener.redirect {
+            Some(redirect) => ListenerMode::Redirect(Arc::new(redirect.clone())),
+            None => {
+                let listener_service = proxy_service.for_listener(&listener)?;
+                listener_service.start_rate_limit_sweepers();
+                ListenerMode::Proxy(Arc::new(RwLock::new(listener_service)))
+            }
+        };
+        
+        // Bind and load certificates up front so misconfiguration fails startup
+        let tcp_listener = bind(listener.listen_addr)
+            .with_context(|| format!("Failed to bind listener {}", name))?;
+        
+        match &listener.tls {
+            Some(tls_config) => {
+                let server_config = tls::build_server_config(tls_config)?;
+                let acceptor = TlsAcceptor::from(server_config);
+                listeners.spawn(serve_https(name, tcp_listener, acceptor, mode));
+            }
+            None => {
+                listeners.spawn(serve_http(name, tcp_listener, mode));
+            }
+        }
+    }
+    
+    // Listeners only return on failure, which brings the whole proxy down
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+    
+    Ok(())
+}
+
+/// What a listener does with the requests it accepts
+#[derive(Clone)]
+enum ListenerMode {
+    Proxy(Arc<RwLock<ProxyService>>),
+    Redirect(Arc<HttpsRedirectConfig>),
+}
+
+fn bind(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    
+    Ok(listener)
+}
+
+async fn serve_http(
+    name: String,
+    listener: std::net::TcpListener,
+    mode: ListenerMode,
+) -> Result<()> {
+    let addr = listener.local_addr()?;
     
     // Create service function
-    let make_svc = make_service_fn(move |_conn| {
-        let proxy_service = proxy_service.clone();
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let mode = mode.clone();
+        let conn_info = ConnectionInfo {
+            peer_addr: conn.remote_addr(),
+            client_cert: None,
+        };
         
         async move {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                let proxy_service = proxy_service.clone();
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                let mode = mode.clone();
+                req.extensions_mut().insert(conn_info.clone());
                 
                 async move {
-                    handle_request(proxy_service, req).await
+                    handle_request(mode, req).await
                 }
             }))
         }
     });
     
     // Create server
-    let server = Server::bind(&addr).serve(make_svc);
+    let server = Server::from_tcp(listener)?.serve(make_svc);
     
-    info!("Reverse proxy listening on http://{}", addr);
+    info!("Reverse proxy listener {} listening on http://{}", name, addr);
     
     // Run the server
     if let Err(e) = server.await {
-        error!("Server error: {}", e);
-        anyhow::bail!("Server error: {}", e);
+        error!("Server error on listener {}: {}", name, e);
+        anyhow::bail!("Server error on listener {}: {}", name, e);
     }
     
     Ok(())
 }
 
+async fn serve_https(
+    name: String,
+    listener: std::net::TcpListener,
+    acceptor: TlsAcceptor,
+    mode: ListenerMode,
+) -> Result<()> {
+    let listener = TcpListener::from_std(listener)?;
+    
+    info!("Reverse proxy listener {} listening on https://{}", name, listener.local_addr()?);
+    
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Usually a transient resource limit such as EMFILE, back off briefly
+                error!("Failed to accept connection on listener {}: {}", name, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        
+        let acceptor = acceptor.clone();
+        let mode = mode.clone();
+        
+        // Handshake on the connection task so a slow client can't stall the accept loop
+        tokio::spawn(async move {
+            let tls_stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls_stream)) => tls_stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", peer_addr, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer_addr);
+                    return;
+                }
+            };
+            
+            // Only present when the listener verifies client certificates
+            let client_cert = tls_stream.get_ref().1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| ClientCertInfo::from_der(&cert.0))
+                .map(Arc::new);
+            
+            let conn_info = ConnectionInfo { peer_addr, client_cert };
+            
+            let service = service_fn(move |mut req: Request<Body>| {
+                let mode = mode.clone();
+                req.extensions_mut().insert(conn_info.clone());
+                
+                async move {
+                    handle_request(mode, req).await
+                }
+            });
+            
+            if let Err(e) = Http::new().serve_connection(tls_stream, service).await {
+                debug!("Error serving connection from {}: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
 async fn handle_request(
-    proxy_service: Arc<RwLock<ProxyService>>,
+    mode: ListenerMode,
     req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
-    match proxy_service.read().await.proxy_request(req).await {
-        Ok(response) => Ok(response),
-        Err(e) => {
-            error!("Error handling request: {}", e);
-            
-            // Return a 500 error response
-            let response = Response::builder()
-                .status(500)
-                .body(Body::from(format!("Proxy error: {}", e)))
+    let proxy_service = match mode {
+        ListenerMode::Proxy(proxy_service) => proxy_service,
+        ListenerMode::Redirect(redirect) => return Ok(redirect_to_https(&redirect, &req)),
+    };
+    
+    let response = proxy_service.read().await.proxy_request(req).await;
+    
+    Ok(response)
+}
+
+fn redirect_to_https(redirect: &HttpsRedirectConfig, req: &Request<Body>) -> Response<Body> {
+    let host = req.headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().host());
+    
+    let host = match host {
+        Some(host) if !host.is_empty() => strip_port(host),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Missing Host header"))
                 .unwrap();
-            
-            Ok(response)
         }
+    };
+    
+    let path = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    
+    let location = if redirect.https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, redirect.https_port, path)
+    };
+    
+    debug!("Redirecting {} to {}", req.uri(), location);
+    
+    // The status was validated with the configuration
+    Response::builder()
+        .status(redirect.status)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap_or_else(|_| {
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Invalid Host header"))
+                .unwrap()
+        })
+}
+
+fn strip_port(host: &str) -> &str {
+    // IPv6 literals keep their brackets, e.g. "[::1]:80"
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
     }
-} 
+    
+    host.rsplit_once(':').map(|(host, _)| host).unwrap_or(host)
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerName, SignatureScheme};
+use tracing::{debug, info, warn};
+use x509_parser::extensions::GeneralName;
+
+use crate::config::{TlsConfig, UpstreamTlsConfig};
+use crate::error::{ProxyError, ProxyResult};
+
+// Schemes offered to the private key when checking it against its certificate
+const KEY_CHECK_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+/// Build the rustls server configuration for a TLS listener
+pub fn build_server_config(config: &TlsConfig) -> ProxyResult<Arc<rustls::ServerConfig>> {
+    let resolver = SniCertResolver::from_config(config)?;
+
+    let client_cert_verifier = match &config.client_ca_path {
+        Some(client_ca_path) => AllowAnyAuthenticatedClient::new(load_root_store(client_ca_path)?).boxed(),
+        None => NoClientAuth::boxed(),
+    };
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_cert_verifier)
+        .with_cert_resolver(Arc::new(resolver));
+
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(server_config))
+}
+
+/// Identity taken from a verified client certificate
+#[derive(Debug, Clone)]
+pub struct ClientCertInfo {
+    /// Subject distinguished name, e.g. "CN=client, O=Example"
+    pub subject: String,
+    /// Subject alternative names, e.g. "DNS:client.example.com"
+    pub sans: Vec<String>,
+}
+
+impl ClientCertInfo {
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san.value.general_names.iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+                    GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+                    GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+                    GeneralName::IPAddress(ip) => format_ip(ip).map(|ip| format!("IP:{}", ip)),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(ClientCertInfo {
+            subject: cert.subject().to_string(),
+            sans,
+        })
+    }
+
+    /// Whether the subject or any SAN matches the pattern
+    pub fn matches(&self, pattern: &Regex) -> bool {
+        pattern.is_match(&self.subject) || self.sans.iter().any(|san| pattern.is_match(san))
+    }
+}
+
+fn format_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+/// Build the rustls client configuration used to connect to upstream servers
+pub fn build_client_config(config: Option<&UpstreamTlsConfig>) -> ProxyResult<rustls::ClientConfig> {
+    let roots = match config.and_then(|tls| tls.ca_path.as_deref()) {
+        Some(ca_path) => load_root_store(ca_path)?,
+        None => NATIVE_ROOTS.clone(),
+    };
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    let client_cert = config.and_then(|tls| tls.client_cert.as_deref().zip(tls.client_key.as_deref()));
+
+    let mut client_config = match client_cert {
+        Some((cert_path, key_path)) => {
+            let (certs, key, _) = load_cert_and_key(cert_path, key_path)?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    if config.is_some_and(|tls| tls.insecure_skip_verify) {
+        warn!("Upstream TLS certificate verification is disabled");
+        client_config.dangerous().set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    Ok(client_config)
+}
+
+/// Client configuration for backends that only speak plain HTTP, which trusts no roots
+pub fn build_plain_client_config() -> rustls::ClientConfig {
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth()
+}
+
+fn load_root_store(path: &str) -> ProxyResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(path)? {
+        roots.add(&cert)
+            .map_err(|e| ProxyError::TlsError(format!("Invalid CA certificate in {}: {}", path, e)))?;
+    }
+
+    Ok(roots)
+}
+
+// System roots are loaded once and shared by every backend client
+static NATIVE_ROOTS: Lazy<RootCertStore> = Lazy::new(native_root_store);
+
+fn native_root_store() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(e) = roots.add(&Certificate(cert.0)) {
+                    debug!("Skipping invalid system CA certificate: {}", e);
+                }
+            }
+        }
+        Err(e) => warn!("Failed to load system CA certificates: {}", e),
+    }
+
+    if roots.is_empty() {
+        warn!("No system CA certificates found, upstream HTTPS will fail without a ca_path");
+    }
+
+    roots
+}
+
+/// Accepts any upstream certificate, used for insecure_skip_verify
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Picks the certificate for a connection from the ClientHello SNI
+struct SniCertResolver {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    // Keyed by the parent domain, so "*.example.com" is stored as "example.com"
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniCertResolver {
+    fn from_config(config: &TlsConfig) -> ProxyResult<Self> {
+        let default = Arc::new(load_certified_key(&config.cert_path, &config.key_path)?);
+        let mut exact = HashMap::new();
+        let mut wildcard = HashMap::new();
+
+        for certificate in &config.certificates {
+            let key = Arc::new(load_certified_key(&certificate.cert_path, &certificate.key_path)?);
+            let server_name = certificate.server_name.to_ascii_lowercase();
+
+            info!("Loaded TLS certificate for {}", server_name);
+
+            match server_name.strip_prefix("*.") {
+                Some(domain) => wildcard.insert(domain.to_string(), key),
+                None => exact.insert(server_name, key),
+            };
+        }
+
+        Ok(SniCertResolver { exact, wildcard, default })
+    }
+
+    fn lookup(&self, server_name: &str) -> Option<&Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+
+        if let Some(key) = self.exact.get(&server_name) {
+            return Some(key);
+        }
+
+        // A wildcard only covers a single label
+        server_name.split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent))
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello.server_name()
+            .and_then(|server_name| self.lookup(server_name))
+            .unwrap_or(&self.default);
+
+        Some(key.clone())
+    }
+}
+
+/// Load a certificate chain and its private key, checking that they belong together
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> ProxyResult<CertifiedKey> {
+    let (certs, _, signing_key) = load_cert_and_key(cert_path, key_path)?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Load a certificate chain and its private key, checking that they belong together
+fn load_cert_and_key(
+    cert_path: &str,
+    key_path: &str,
+) -> ProxyResult<(Vec<Certificate>, PrivateKey, Arc<dyn SigningKey>)> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| ProxyError::TlsError(format!("Unsupported private key type in {}", key_path)))?;
+
+    ensure_key_matches(&certs[0], signing_key.as_ref())
+        .map_err(|e| ProxyError::TlsError(format!(
+            "Private key {} does not match certificate {}: {}",
+            key_path, cert_path, e
+        )))?;
+
+    Ok((certs, key, signing_key))
+}
+
+/// Load a PEM encoded certificate chain
+pub fn load_certs(path: &str) -> ProxyResult<Vec<Certificate>> {
+    let file = File::open(path)
+        .map_err(|e| ProxyError::TlsError(format!("Failed to open certificate file {}: {}", path, e)))?;
+    let mut reader = BufReader::new(file);
+
+    let certs = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| ProxyError::TlsError(format!("Failed to parse certificate file {}: {}", path, e)))?;
+
+    if certs.is_empty() {
+        return Err(ProxyError::TlsError(format!("No certificates found in {}", path)));
+    }
+
+    debug!("Loaded {} certificate(s) from {}", certs.len(), path);
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first PEM encoded private key (PKCS#8, PKCS#1 or SEC1)
+pub fn load_private_key(path: &str) -> ProxyResult<PrivateKey> {
+    let file = File::open(path)
+        .map_err(|e| ProxyError::TlsError(format!("Failed to open private key file {}: {}", path, e)))?;
+    let mut reader = BufReader::new(file);
+
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| ProxyError::TlsError(format!("Failed to parse private key file {}: {}", path, e)))?;
+
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => break,
+        }
+    }
+
+    Err(ProxyError::TlsError(format!("No private key found in {}", path)))
+}
+
+/// Sign a probe message with the key and verify it with the certificate's public key
+fn ensure_key_matches(cert: &Certificate, signing_key: &dyn SigningKey) -> Result<(), String> {
+    let signer = signing_key.choose_scheme(KEY_CHECK_SCHEMES)
+        .ok_or_else(|| "no supported signature scheme for private key".to_string())?;
+
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        scheme => return Err(format!("unexpected signature scheme {:?}", scheme)),
+    };
+
+    let message = b"ranx certificate key check";
+    let signature = signer.sign(message)
+        .map_err(|e| format!("failed to sign with private key: {}", e))?;
+
+    let end_entity = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| format!("invalid certificate: {:?}", e))?;
+
+    end_entity.verify_signature(algorithm, message, &signature)
+        .map_err(|_| "public key mismatch".to_string())
+}